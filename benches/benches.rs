@@ -3,16 +3,17 @@ use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
 use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
 use crossbeam_utils::sync::WaitGroup;
 use kvs::{
-    KvServer, KvStore, KvsEngine, Request, Response, SledKvsEngine,
+    KvServer, KvStore, KvsEngine, Request, Response, SledKvsEngine, SyncPolicy,
     thread_pool::{RayonThreadPool, SharedQueueThreadPool, ThreadPool},
 };
 use tempfile::TempDir;
 
+#[allow(dead_code)]
 fn kvs_write(c: &mut Criterion) {
     c.bench_function("kvs_write", |b| {
         b.iter(|| {
             let temp_dir = TempDir::new().unwrap();
-            let mut store = KvStore::open(temp_dir.path()).unwrap();
+            let store = KvStore::open(temp_dir.path()).unwrap();
 
             for i in 0..100 {
                 store
@@ -23,11 +24,12 @@ fn kvs_write(c: &mut Criterion) {
     });
 }
 
+#[allow(dead_code)]
 fn sled_write(c: &mut Criterion) {
     c.bench_function("sled_function", |b| {
         b.iter(|| {
             let temp = TempDir::new().unwrap();
-            let mut store = SledKvsEngine::open(temp.path()).unwrap();
+            let store = SledKvsEngine::open(temp.path()).unwrap();
             for i in 0..100 {
                 store
                     .set(format!("key{}", i), format!("value{}", i))
@@ -39,7 +41,7 @@ fn sled_write(c: &mut Criterion) {
 
 fn kvs_read(c: &mut Criterion) {
     let temp = TempDir::new().unwrap();
-    let mut store = KvStore::open(temp.path()).unwrap();
+    let store = KvStore::open(temp.path()).unwrap();
     for i in 0..1000 {
         store
             .set(format!("key{}", i), format!("value{}", i))
@@ -54,9 +56,38 @@ fn kvs_read(c: &mut Criterion) {
     });
 }
 
+// Reads straight from the store on several threads at once; the time per
+// iteration should stay roughly flat as threads are added.
+fn kvs_concurrent_read(c: &mut Criterion) {
+    let temp = TempDir::new().unwrap();
+    let store = KvStore::open(temp.path()).unwrap();
+    for i in 0..1000 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    let mut group = c.benchmark_group("kvs_concurrent_read");
+    for threads in [1, 2, 4, 8] {
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &threads, |b, &n| {
+            b.iter(|| {
+                std::thread::scope(|s| {
+                    for _ in 0..n {
+                        s.spawn(|| {
+                            for i in 0..1000 {
+                                store.get(format!("key{}", i)).unwrap();
+                            }
+                        });
+                    }
+                });
+            });
+        });
+    }
+    group.finish();
+}
+
 fn sled_read(c: &mut Criterion) {
     let temp = TempDir::new().unwrap();
-    let mut store = SledKvsEngine::open(temp.path()).unwrap();
+    let store = SledKvsEngine::open(temp.path()).unwrap();
     for i in 0..1000 {
         store
             .set(format!("key{}", i), format!("value{}", i))
@@ -71,9 +102,68 @@ fn sled_read(c: &mut Criterion) {
     });
 }
 
+fn sync_policies() -> Vec<(&'static str, SyncPolicy)> {
+    vec![
+        ("never", SyncPolicy::Never),
+        ("always", SyncPolicy::Always),
+        ("group-commit", SyncPolicy::GroupCommit),
+        (
+            "interval-10ms",
+            SyncPolicy::Interval(std::time::Duration::from_millis(10)),
+        ),
+    ]
+}
+
+// 8 threads writing 25 keys each under every sync policy. Group commit
+// should land close to never and well below always.
+fn write_concurrently(store: &impl KvsEngine) {
+    std::thread::scope(|s| {
+        for t in 0..8 {
+            let store = store.clone();
+            s.spawn(move || {
+                for i in 0..25 {
+                    store
+                        .set(format!("key{}_{}", t, i), format!("value{}", i))
+                        .unwrap();
+                }
+            });
+        }
+    });
+}
+
+fn kvs_sync_policy(c: &mut Criterion) {
+    let mut group = c.benchmark_group("kvs_sync_policy");
+    for (name, policy) in sync_policies() {
+        let temp = TempDir::new().unwrap();
+        let store = KvStore::builder(temp.path())
+            .sync_policy(policy)
+            .open()
+            .unwrap();
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| write_concurrently(&store));
+        });
+    }
+    group.finish();
+}
+
+fn sled_sync_policy(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sled_sync_policy");
+    for (name, policy) in sync_policies() {
+        let temp = TempDir::new().unwrap();
+        let store = SledKvsEngine::open_with_sync(temp.path(), policy).unwrap();
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| write_concurrently(&store));
+        });
+    }
+    group.finish();
+}
+
 pub fn thread_counts() -> Vec<u32> {
     // Reduced for faster benchmarking
-    vec![1, 4, num_cpus::get() as u32, 32]
+    let mut counts = vec![1, 4, num_cpus::get() as u32, 32];
+    counts.sort_unstable();
+    counts.dedup();
+    counts
 }
 
 pub fn send_request(addr: SocketAddr, request: &Request) -> Response {
@@ -92,6 +182,7 @@ pub fn available_port() -> SocketAddr {
     addr
 }
 
+#[allow(dead_code)]
 fn write_rayon_kvstore(c: &mut Criterion) {
     let mut group = c.benchmark_group("write_queued_kvstore");
     let inputs = thread_counts();
@@ -124,8 +215,8 @@ fn write_rayon_kvstore(c: &mut Criterion) {
                         let response = send_request(
                             addr,
                             &Request::Set {
-                                key: format!("key{}", i),
-                                value: "value".to_string(),
+                                key: format!("key{}", i).into_bytes(),
+                                value: b"value".to_vec(),
                             },
                         );
                         assert!(matches!(response, Response::Ok(_)));
@@ -140,6 +231,7 @@ fn write_rayon_kvstore(c: &mut Criterion) {
     group.finish();
 }
 
+#[allow(dead_code)]
 fn write_queued_kvstore(c: &mut Criterion) {
     let mut group = c.benchmark_group("write_queued_kvstore");
     let inputs = thread_counts();
@@ -172,8 +264,8 @@ fn write_queued_kvstore(c: &mut Criterion) {
                         let response = send_request(
                             addr,
                             &Request::Set {
-                                key: format!("key{}", i),
-                                value: "value".to_string(),
+                                key: format!("key{}", i).into_bytes(),
+                                value: b"value".to_vec(),
                             },
                         );
                         assert!(matches!(response, Response::Ok(_)));
@@ -188,6 +280,7 @@ fn write_queued_kvstore(c: &mut Criterion) {
     group.finish();
 }
 
+#[allow(dead_code)]
 fn write_rayon_sled(c: &mut Criterion) {
     let mut group = c.benchmark_group("write_queued_kvstore");
     let inputs = thread_counts();
@@ -220,8 +313,8 @@ fn write_rayon_sled(c: &mut Criterion) {
                         let response = send_request(
                             addr,
                             &Request::Set {
-                                key: format!("key{}", i),
-                                value: "value".to_string(),
+                                key: format!("key{}", i).into_bytes(),
+                                value: b"value".to_vec(),
                             },
                         );
                         assert!(matches!(response, Response::Ok(_)));
@@ -271,8 +364,8 @@ fn comparison(c: &mut Criterion) {
                             let response = send_request(
                                 addr,
                                 &Request::Set {
-                                    key: format!("key{}", i),
-                                    value: "value".to_string(),
+                                    key: format!("key{}", i).into_bytes(),
+                                    value: b"value".to_vec(),
                                 },
                             );
                             assert!(matches!(response, Response::Ok(_)));
@@ -315,8 +408,8 @@ fn comparison(c: &mut Criterion) {
                             let response = send_request(
                                 addr,
                                 &Request::Set {
-                                    key: format!("key{}", i),
-                                    value: "value".to_string(),
+                                    key: format!("key{}", i).into_bytes(),
+                                    value: b"value".to_vec(),
                                 },
                             );
                             assert!(matches!(response, Response::Ok(_)));
@@ -359,8 +452,8 @@ fn comparison(c: &mut Criterion) {
                             let response = send_request(
                                 addr,
                                 &Request::Set {
-                                    key: format!("key{}", i),
-                                    value: "value".to_string(),
+                                    key: format!("key{}", i).into_bytes(),
+                                    value: b"value".to_vec(),
                                 },
                             );
                             assert!(matches!(response, Response::Ok(_)));
@@ -402,7 +495,7 @@ fn comparison(c: &mut Criterion) {
                             let response = send_request(
                                 addr,
                                 &Request::Get {
-                                    key: format!("key{}", i),
+                                    key: format!("key{}", i).into_bytes(),
                                 },
                             );
                             assert!(matches!(response, Response::Ok(_)));
@@ -445,7 +538,7 @@ fn comparison(c: &mut Criterion) {
                             let response = send_request(
                                 addr,
                                 &Request::Get {
-                                    key: format!("key{}", i),
+                                    key: format!("key{}", i).into_bytes(),
                                 },
                             );
                             assert!(matches!(response, Response::Ok(_)));
@@ -488,7 +581,7 @@ fn comparison(c: &mut Criterion) {
                             let response = send_request(
                                 addr,
                                 &Request::Get {
-                                    key: format!("key{}", i),
+                                    key: format!("key{}", i).into_bytes(),
                                 },
                             );
                             assert!(matches!(response, Response::Ok(_)));
@@ -504,6 +597,7 @@ fn comparison(c: &mut Criterion) {
     group.finish();
 }
 
+#[allow(dead_code)]
 fn read_rayon_kvstore(c: &mut Criterion) {
     let mut group = c.benchmark_group("write_queued_kvstore");
     let inputs = thread_counts();
@@ -536,7 +630,7 @@ fn read_rayon_kvstore(c: &mut Criterion) {
                         let response = send_request(
                             addr,
                             &Request::Get {
-                                key: format!("key{}", i),
+                                key: format!("key{}", i).into_bytes(),
                             },
                         );
                         assert!(matches!(response, Response::Ok(_)));
@@ -551,6 +645,7 @@ fn read_rayon_kvstore(c: &mut Criterion) {
     group.finish();
 }
 
+#[allow(dead_code)]
 fn read_queued_kvstore(c: &mut Criterion) {
     let mut group = c.benchmark_group("write_queued_kvstore");
     let inputs = thread_counts();
@@ -583,7 +678,7 @@ fn read_queued_kvstore(c: &mut Criterion) {
                         let response = send_request(
                             addr,
                             &Request::Get {
-                                key: format!("key{}", i),
+                                key: format!("key{}", i).into_bytes(),
                             },
                         );
                         assert!(matches!(response, Response::Ok(_)));
@@ -598,6 +693,7 @@ fn read_queued_kvstore(c: &mut Criterion) {
     group.finish();
 }
 
+#[allow(dead_code)]
 fn read_rayon_sled(c: &mut Criterion) {
     let mut group = c.benchmark_group("write_queued_kvstore");
     let inputs = thread_counts();
@@ -630,7 +726,7 @@ fn read_rayon_sled(c: &mut Criterion) {
                         let response = send_request(
                             addr,
                             &Request::Get {
-                                key: format!("key{}", i),
+                                key: format!("key{}", i).into_bytes(),
                             },
                         );
                         assert!(matches!(response, Response::Ok(_)));
@@ -669,4 +765,23 @@ criterion_group! {
         .measurement_time(std::time::Duration::from_millis(500));
     targets = comparison
 }
-criterion_main!(benches);
+
+criterion_group! {
+    name = durability;
+    config = Criterion::default()
+        .sample_size(10)
+        .warm_up_time(std::time::Duration::from_millis(100))
+        .measurement_time(std::time::Duration::from_millis(500));
+    targets = kvs_sync_policy, sled_sync_policy
+}
+
+criterion_group! {
+    name = reads;
+    config = Criterion::default()
+        .sample_size(10)
+        .warm_up_time(std::time::Duration::from_millis(100))
+        .measurement_time(std::time::Duration::from_millis(500));
+    targets = kvs_read, sled_read, kvs_concurrent_read
+}
+
+criterion_main!(benches, durability, reads);
@@ -0,0 +1,41 @@
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// A thread that runs a task at a fixed interval. Dropping it stops the
+/// thread after the task has run one last time.
+pub(crate) struct Periodic {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Periodic {
+    pub(crate) fn spawn(interval: Duration, mut task: impl FnMut() + Send + 'static) -> Periodic {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            loop {
+                let last_round = !matches!(
+                    stopped.recv_timeout(interval),
+                    Err(RecvTimeoutError::Timeout)
+                );
+                task();
+                if last_round {
+                    break;
+                }
+            }
+        });
+        Periodic {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Periodic {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
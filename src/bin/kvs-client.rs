@@ -1,7 +1,7 @@
 use std::net::{Shutdown, SocketAddr, TcpStream};
 
 use clap::{Parser, Subcommand};
-use kvs::{Request, Response};
+use kvs::{Event, Op, Request, Response};
 // No local Result type — we handle errors explicitly with unwrap_or_else/eprintln
 
 #[derive(Parser)]
@@ -9,6 +9,9 @@ use kvs::{Request, Response};
 struct Cli {
     #[command(subcommand)]
     command: Command,
+    /// Work on this tree of the store instead of the default keyspace
+    #[arg(long, global = true)]
+    namespace: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -16,6 +19,9 @@ enum Command {
     Set {
         key: String,
         value: String,
+        /// Let the key expire after this many seconds
+        #[arg(long)]
+        ttl: Option<u64>,
         #[arg(long, default_value = "127.0.0.1:4000")]
         addr: String,
     },
@@ -29,10 +35,81 @@ enum Command {
         #[arg(long, default_value = "127.0.0.1:4000")]
         addr: String,
     },
+    /// Print the seconds left before KEY expires
+    Ttl {
+        key: String,
+        #[arg(long, default_value = "127.0.0.1:4000")]
+        addr: String,
+    },
+    /// Remove the expiry time of KEY
+    Persist {
+        key: String,
+        #[arg(long, default_value = "127.0.0.1:4000")]
+        addr: String,
+    },
+    /// Add DELTA to the integer stored at KEY and print the result
+    Incr {
+        key: String,
+        #[arg(default_value_t = 1, allow_negative_numbers = true)]
+        delta: i64,
+        #[arg(long, default_value = "127.0.0.1:4000")]
+        addr: String,
+    },
+    /// Append SUFFIX to the value of KEY
+    Append {
+        key: String,
+        suffix: String,
+        #[arg(long, default_value = "127.0.0.1:4000")]
+        addr: String,
+    },
+    /// Set KEY to --new, or remove it without --new, but only if its value
+    /// is --expected, or it does not exist without --expected
+    Cas {
+        key: String,
+        #[arg(long)]
+        expected: Option<String>,
+        #[arg(long)]
+        new: Option<String>,
+        #[arg(long, default_value = "127.0.0.1:4000")]
+        addr: String,
+    },
+    /// List keys from START (inclusive) to END (exclusive) in key order
+    Scan {
+        start: Option<String>,
+        end: Option<String>,
+        /// List only keys starting with this prefix
+        #[arg(long, conflicts_with_all = ["start", "end"])]
+        prefix: Option<String>,
+        /// Stop after this many keys
+        #[arg(long)]
+        limit: Option<usize>,
+        #[arg(long, default_value = "127.0.0.1:4000")]
+        addr: String,
+    },
+    /// Print every change to keys starting with PREFIX until interrupted
+    Watch {
+        #[arg(default_value = "")]
+        prefix: String,
+        #[arg(long, default_value = "127.0.0.1:4000")]
+        addr: String,
+    },
+}
+
+/// Helper: address `request` to `namespace`, if given
+fn in_namespace(namespace: Option<&str>, request: Request) -> Request {
+    match namespace {
+        Some(name) => Request::Tree {
+            name: name.to_owned(),
+            request: Box::new(request),
+        },
+        None => request,
+    }
 }
 
 /// Helper: connect to server, send request, read response
-pub fn send_request(addr: &str, request: &Request) -> Response {
+pub fn send_request(addr: &str, namespace: Option<&str>, request: Request) -> Response {
+    let request = in_namespace(namespace, request);
+
     // Parse address
     let addr: SocketAddr = addr.parse().unwrap_or_else(|e| {
         eprintln!("{}", e);
@@ -46,7 +123,7 @@ pub fn send_request(addr: &str, request: &Request) -> Response {
     });
 
     // Write request as JSON
-    serde_json::to_writer(&mut stream, request).unwrap_or_else(|e| {
+    serde_json::to_writer(&mut stream, &request).unwrap_or_else(|e| {
         eprintln!("{}", e);
         std::process::exit(1);
     });
@@ -66,39 +143,231 @@ pub fn send_request(addr: &str, request: &Request) -> Response {
     response
 }
 
+/// Helper: start a watch and print the events the server streams back
+fn watch(addr: &str, namespace: Option<&str>, prefix: String) {
+    let addr: SocketAddr = addr.parse().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let mut stream = TcpStream::connect(addr).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+
+    // The write half stays open: the server ends the watch once it closes
+    let request = in_namespace(
+        namespace,
+        Request::Watch {
+            prefix: prefix.into(),
+        },
+    );
+    serde_json::to_writer(&mut stream, &request).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+
+    let responses = serde_json::Deserializer::from_reader(&stream).into_iter::<Response>();
+    for response in responses {
+        let response = response.unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+        match response {
+            Response::Ok(_) => {}
+            Response::Event(Event { key, op, value }) => {
+                let key = String::from_utf8_lossy(&key);
+                match (op, value) {
+                    (Op::Set, Some(value)) => {
+                        println!("set\t{}\t{}", key, String::from_utf8_lossy(&value))
+                    }
+                    (Op::Set, None) => println!("set\t{}", key),
+                    (Op::Remove, _) => println!("rm\t{}", key),
+                }
+            }
+            Response::Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            _ => unexpected_response(),
+        }
+    }
+}
+
+fn unexpected_response() -> ! {
+    eprintln!("Unexpected response from server");
+    std::process::exit(1);
+}
+
 fn main() {
     let cli = Cli::parse();
+    let namespace = cli.namespace.as_deref();
     match cli.command {
-        Command::Set { key, value, addr } => {
-            let response = send_request(&addr, &Request::Set { key, value });
+        Command::Set {
+            key,
+            value,
+            ttl,
+            addr,
+        } => {
+            let request = match ttl {
+                Some(secs) => Request::SetWithTtl {
+                    key: key.into(),
+                    value: value.into(),
+                    ttl_ms: secs.saturating_mul(1000),
+                },
+                None => Request::Set {
+                    key: key.into(),
+                    value: value.into(),
+                },
+            };
+            let response = send_request(&addr, namespace, request);
             match response {
                 Response::Ok(_) => {}
                 Response::Err(e) => {
                     eprintln!("{}", e);
                     std::process::exit(1);
                 }
+                _ => unexpected_response(),
             }
         }
         Command::Get { key, addr } => {
-            let response = send_request(&addr, &Request::Get { key });
+            let response = send_request(&addr, namespace, Request::Get { key: key.into() });
             match response {
-                Response::Ok(Some(value)) => println!("{}", value),
+                Response::Ok(Some(value)) => println!("{}", String::from_utf8_lossy(&value)),
                 Response::Ok(None) => println!("Key not found"),
                 Response::Err(e) => {
                     eprintln!("{}", e);
                     std::process::exit(1);
                 }
+                _ => unexpected_response(),
             }
         }
         Command::Rm { key, addr } => {
-            let response = send_request(&addr, &Request::Remove { key });
+            let response = send_request(&addr, namespace, Request::Remove { key: key.into() });
+            match response {
+                Response::Ok(_) => {}
+                Response::Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+                _ => unexpected_response(),
+            }
+        }
+        Command::Ttl { key, addr } => {
+            match send_request(&addr, namespace, Request::Ttl { key: key.into() }) {
+                Response::Ttl(Some(millis)) => println!("{}", millis.div_ceil(1000)),
+                Response::Ttl(None) => println!("No expiry"),
+                Response::Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+                _ => unexpected_response(),
+            }
+        }
+        Command::Persist { key, addr } => {
+            match send_request(&addr, namespace, Request::Persist { key: key.into() }) {
+                Response::Ok(_) => {}
+                Response::Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+                _ => unexpected_response(),
+            }
+        }
+        Command::Incr { key, delta, addr } => {
+            let request = Request::Incr {
+                key: key.into(),
+                delta,
+            };
+            match send_request(&addr, namespace, request) {
+                Response::Counter(value) => println!("{}", value),
+                Response::Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+                _ => unexpected_response(),
+            }
+        }
+        Command::Append { key, suffix, addr } => {
+            let request = Request::Append {
+                key: key.into(),
+                suffix: suffix.into(),
+            };
+            match send_request(&addr, namespace, request) {
+                Response::Ok(_) => {}
+                Response::Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+                _ => unexpected_response(),
+            }
+        }
+        Command::Cas {
+            key,
+            expected,
+            new,
+            addr,
+        } => {
+            let request = Request::CompareAndSwap {
+                key: key.into(),
+                expected: expected.map(String::into_bytes),
+                new: new.map(String::into_bytes),
+            };
+            let response = send_request(&addr, namespace, request);
             match response {
                 Response::Ok(_) => {}
+                Response::Conflict(Some(current)) => {
+                    eprintln!(
+                        "Value mismatch, current value: {}",
+                        String::from_utf8_lossy(&current)
+                    );
+                    std::process::exit(1);
+                }
+                Response::Conflict(None) => {
+                    eprintln!("Value mismatch, key not found");
+                    std::process::exit(1);
+                }
+                Response::Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+                _ => unexpected_response(),
+            }
+        }
+        Command::Scan {
+            start,
+            end,
+            prefix,
+            limit,
+            addr,
+        } => {
+            let request = match prefix {
+                Some(prefix) => Request::ScanPrefix {
+                    prefix: prefix.into(),
+                    limit,
+                },
+                None => Request::Scan {
+                    start: start.map(String::into_bytes),
+                    end: end.map(String::into_bytes),
+                    limit,
+                },
+            };
+            match send_request(&addr, namespace, request) {
+                Response::Pairs(pairs) => {
+                    for (key, value) in pairs {
+                        println!(
+                            "{}\t{}",
+                            String::from_utf8_lossy(&key),
+                            String::from_utf8_lossy(&value)
+                        );
+                    }
+                }
                 Response::Err(e) => {
                     eprintln!("{}", e);
                     std::process::exit(1);
                 }
+                _ => unexpected_response(),
             }
         }
+        Command::Watch { prefix, addr } => watch(&addr, namespace, prefix),
     }
 }
@@ -3,7 +3,7 @@ use std::{env, fmt::Display, fs::create_dir, net::SocketAddr};
 use clap::Parser;
 use kvs::thread_pool::SharedQueueThreadPool;
 use kvs::thread_pool::ThreadPool;
-use kvs::{KvServer, KvStore, SledKvsEngine};
+use kvs::{EncryptionKey, KvServer, KvStore, SledKvsEngine, SyncPolicy};
 use log::info;
 
 #[derive(Parser)]
@@ -13,6 +13,51 @@ struct Cli {
     addr: String,
     #[arg(long, default_value = "kvs")]
     engine: String,
+    /// Compact once this many bytes in the log are dead (kvs engine)
+    #[arg(long)]
+    compaction_threshold: Option<u64>,
+    /// Also compact once this fraction of the log is dead (kvs engine)
+    #[arg(long)]
+    dead_ratio: Option<f64>,
+    /// Only compact the files at least this fraction of which is dead
+    /// (kvs engine)
+    #[arg(long)]
+    file_dead_ratio: Option<f64>,
+    /// Rotate the active log file at this size (kvs engine)
+    #[arg(long)]
+    max_file_size: Option<u64>,
+    /// Size of the write buffer in bytes (kvs engine)
+    #[arg(long)]
+    write_buffer_size: Option<usize>,
+    /// Keep the index within about this many bytes of memory, spilling the
+    /// rest to disk (kvs engine)
+    #[arg(long)]
+    index_memory: Option<usize>,
+    /// Cache up to this many bytes of recently read values (kvs engine)
+    #[arg(long)]
+    cache_size: Option<usize>,
+    /// Compress values of at least this many bytes in the log (kvs engine)
+    #[arg(long)]
+    compress_from: Option<usize>,
+    /// Encrypt the logs with the key in this file, 32 raw bytes or 64 hex
+    /// digits (kvs engine)
+    #[arg(long, conflicts_with = "encryption_key_env")]
+    encryption_key_file: Option<String>,
+    /// Encrypt the logs with the key in this environment variable, as 64
+    /// hex digits (kvs engine)
+    #[arg(long)]
+    encryption_key_env: Option<String>,
+    /// A key the logs may still be encrypted with; repeat for several.
+    /// Compaction moves the data over to the current key (kvs engine)
+    #[arg(long)]
+    previous_key_file: Vec<String>,
+    /// When writes are fsynced: never, always, group-commit or
+    /// interval:<millis>. Defaults to never for kvs and always for sled
+    #[arg(long)]
+    sync: Option<SyncPolicy>,
+    /// Serve the existing data without accepting writes (kvs engine)
+    #[arg(long)]
+    read_only: bool,
 }
 
 #[derive(Debug)]
@@ -96,7 +141,49 @@ pub fn main() {
 
     match engine {
         EngineName::Kvs => {
-            let store = KvStore::open(&current_dir).unwrap_or_else(|e| {
+            let mut builder = KvStore::builder(&current_dir).read_only(cli.read_only);
+            if let Some(policy) = cli.sync {
+                builder = builder.sync_policy(policy);
+            }
+            if let Some(bytes) = cli.compaction_threshold {
+                builder = builder.compaction_threshold(bytes);
+            }
+            if let Some(ratio) = cli.dead_ratio {
+                builder = builder.dead_ratio(ratio);
+            }
+            if let Some(ratio) = cli.file_dead_ratio {
+                builder = builder.file_dead_ratio(ratio);
+            }
+            if let Some(bytes) = cli.max_file_size {
+                builder = builder.max_file_size(bytes);
+            }
+            if let Some(bytes) = cli.write_buffer_size {
+                builder = builder.write_buffer_size(bytes);
+            }
+            if let Some(bytes) = cli.index_memory {
+                builder = builder.index_memory(bytes);
+            }
+            if let Some(bytes) = cli.cache_size {
+                builder = builder.value_cache(bytes);
+            }
+            if let Some(bytes) = cli.compress_from {
+                builder = builder.compress_values(bytes);
+            }
+            let load = |key: kvs::Result<EncryptionKey>| {
+                key.unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                })
+            };
+            if let Some(path) = &cli.encryption_key_file {
+                builder = builder.encryption_key(load(EncryptionKey::from_file(path)));
+            } else if let Some(var) = &cli.encryption_key_env {
+                builder = builder.encryption_key(load(EncryptionKey::from_env(var)));
+            }
+            for path in &cli.previous_key_file {
+                builder = builder.previous_key(load(EncryptionKey::from_file(path)));
+            }
+            let store = builder.open().unwrap_or_else(|e| {
                 eprintln!("{}", e);
                 std::process::exit(1);
             });
@@ -107,7 +194,8 @@ pub fn main() {
             server.run();
         }
         EngineName::Sled => {
-            let store = SledKvsEngine::open(&current_dir).unwrap_or_else(|e| {
+            let sync = cli.sync.unwrap_or(SyncPolicy::Always);
+            let store = SledKvsEngine::open_with_sync(&current_dir, sync).unwrap_or_else(|e| {
                 eprintln!("{}", e);
                 std::process::exit(1);
             });
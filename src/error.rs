@@ -0,0 +1,35 @@
+use failure::Fail;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Errors callers may want to tell apart. Get one back out of a
+/// `failure::Error` with `downcast_ref`.
+#[derive(Debug)]
+pub enum KvsError {
+    /// Another `KvStore` holds the lock on this data directory.
+    Locked(PathBuf),
+    /// A write was attempted on a store opened read-only.
+    ReadOnly,
+    /// A transaction touched a key that was written after it began.
+    Conflict,
+    /// Data on disk is encrypted with a key that was not supplied.
+    WrongKey,
+}
+
+impl fmt::Display for KvsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvsError::Locked(dir) => {
+                write!(f, "{} is already opened by another process", dir.display())
+            }
+            KvsError::ReadOnly => write!(f, "store is opened read-only"),
+            KvsError::Conflict => write!(f, "transaction conflicts with a concurrent write"),
+            KvsError::WrongKey => write!(
+                f,
+                "data is encrypted with a key that was not supplied; wrong encryption key?"
+            ),
+        }
+    }
+}
+
+impl Fail for KvsError {}
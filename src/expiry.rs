@@ -0,0 +1,22 @@
+//! Expiry times are stored as milliseconds since the Unix epoch, so they
+//! keep their meaning across restarts.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often the background reaper looks for expired keys by default.
+pub(crate) const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// The expiry time of a key set now with the given time to live.
+pub(crate) fn expires_at(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+/// Time left until `expires_at`, zero once it has passed.
+pub(crate) fn time_left(expires_at: u64) -> Duration {
+    Duration::from_millis(expires_at.saturating_sub(now_millis()))
+}
@@ -0,0 +1,223 @@
+//! A size-bounded cache of values read from the logs, in front of
+//! `KvStore::get`.
+//!
+//! Values are cached by the location of their record, not by key. A record
+//! never changes once written and file ids are never reused, so an entry
+//! can't go stale: a write or compaction only moves the key to a new
+//! location. Entries for locations that are no longer referenced are
+//! dropped as soon as that happens, or else evicted in time.
+//!
+//! The cache is split into shards, each behind its own lock and evicting
+//! with the CLOCK algorithm: every entry has a referenced bit set on a hit,
+//! and the hand sweeping for a victim clears set bits and evicts the first
+//! entry it finds without one.
+use super::LogPointer;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const SHARDS: usize = 16;
+// rough in-memory size of an entry besides its value
+const ENTRY_OVERHEAD: usize = 64;
+
+/// Counters of a `KvStore` value cache, returned by `KvStore::cache_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Lookups answered from the cache.
+    pub hits: u64,
+    /// Lookups that had to read the log.
+    pub misses: u64,
+    /// Values currently cached.
+    pub entries: u64,
+    /// Approximate memory the cached values take, in bytes.
+    pub bytes: u64,
+    /// Memory the cache may take, in bytes; 0 if it is disabled.
+    pub capacity: u64,
+}
+
+// (file id, offset) of a record
+type Location = (u64, u64);
+
+pub(super) struct ValueCache {
+    shards: Vec<Mutex<Shard>>,
+    capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ValueCache {
+    /// A cache holding about `capacity` bytes of values.
+    pub(super) fn new(capacity: usize) -> ValueCache {
+        ValueCache {
+            shards: (0..SHARDS)
+                .map(|_| Mutex::new(Shard::new(capacity / SHARDS)))
+                .collect(),
+            capacity,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// The cached value of the record at `ptr`, counting a hit or a miss.
+    pub(super) fn get(&self, ptr: &LogPointer) -> Option<Vec<u8>> {
+        let location = location(ptr);
+        let value = self.shard(location).lock().unwrap().get(location);
+        let counter = if value.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    pub(super) fn insert(&self, ptr: &LogPointer, value: Vec<u8>) {
+        let location = location(ptr);
+        self.shard(location).lock().unwrap().insert(location, value);
+    }
+
+    /// Drops the value of the record at `ptr`, which the index no longer
+    /// points at.
+    pub(super) fn remove(&self, ptr: &LogPointer) {
+        let location = location(ptr);
+        self.shard(location).lock().unwrap().remove(location);
+    }
+
+    /// Drops the values of every record in the files `file_ids`.
+    pub(super) fn remove_files(&self, file_ids: &[u64]) {
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            let gone: Vec<Location> = shard
+                .map
+                .keys()
+                .filter(|(file_id, _)| file_ids.contains(file_id))
+                .copied()
+                .collect();
+            for location in gone {
+                shard.remove(location);
+            }
+        }
+    }
+
+    pub(super) fn clear(&self) {
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            let capacity = shard.capacity;
+            *shard = Shard::new(capacity);
+        }
+    }
+
+    pub(super) fn stats(&self) -> CacheStats {
+        let (entries, bytes) = self.shards.iter().fold((0, 0), |(entries, bytes), shard| {
+            let shard = shard.lock().unwrap();
+            (entries + shard.slots.len(), bytes + shard.bytes)
+        });
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: entries as u64,
+            bytes: bytes as u64,
+            capacity: self.capacity as u64,
+        }
+    }
+
+    fn shard(&self, (file_id, offset): Location) -> &Mutex<Shard> {
+        let hash = file_id.wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ offset;
+        &self.shards[(hash % SHARDS as u64) as usize]
+    }
+}
+
+fn location(ptr: &LogPointer) -> Location {
+    (ptr.file_id, ptr.offset)
+}
+
+struct Shard {
+    // location -> index into `slots`
+    map: HashMap<Location, usize>,
+    slots: Vec<Slot>,
+    hand: usize,
+    bytes: usize,
+    capacity: usize,
+}
+
+struct Slot {
+    location: Location,
+    value: Vec<u8>,
+    referenced: bool,
+}
+
+impl Slot {
+    fn size(&self) -> usize {
+        self.value.len() + ENTRY_OVERHEAD
+    }
+}
+
+impl Shard {
+    fn new(capacity: usize) -> Shard {
+        Shard {
+            map: HashMap::new(),
+            slots: Vec::new(),
+            hand: 0,
+            bytes: 0,
+            capacity,
+        }
+    }
+
+    fn get(&mut self, location: Location) -> Option<Vec<u8>> {
+        let slot = &mut self.slots[*self.map.get(&location)?];
+        slot.referenced = true;
+        Some(slot.value.clone())
+    }
+
+    fn insert(&mut self, location: Location, value: Vec<u8>) {
+        let slot = Slot {
+            location,
+            value,
+            referenced: false,
+        };
+        // Too big to ever fit; caching it would only flush everything else
+        if slot.size() > self.capacity {
+            return;
+        }
+        self.remove(location);
+        while self.bytes + slot.size() > self.capacity {
+            self.evict();
+        }
+        self.bytes += slot.size();
+        self.map.insert(location, self.slots.len());
+        self.slots.push(slot);
+    }
+
+    fn remove(&mut self, location: Location) {
+        if let Some(index) = self.map.remove(&location) {
+            self.take(index);
+        }
+    }
+
+    /// Evicts one entry, giving those referenced since the hand last passed
+    /// them another round.
+    fn evict(&mut self) {
+        loop {
+            if self.hand >= self.slots.len() {
+                self.hand = 0;
+            }
+            let slot = &mut self.slots[self.hand];
+            if !slot.referenced {
+                self.map.remove(&slot.location);
+                self.take(self.hand);
+                return;
+            }
+            slot.referenced = false;
+            self.hand += 1;
+        }
+    }
+
+    /// Removes the slot at `index`, whose location is already out of `map`.
+    fn take(&mut self, index: usize) {
+        let slot = self.slots.swap_remove(index);
+        self.bytes -= slot.size();
+        if let Some(moved) = self.slots.get(index) {
+            self.map.insert(moved.location, index);
+        }
+    }
+}
@@ -0,0 +1,171 @@
+//! Authenticated encryption of the data `KvStore` keeps on disk, with
+//! XChaCha20-Poly1305 and a random nonce per message. A sealed message is
+//!
+//! ```text
+//! | key_id: u32 | nonce: [u8; 24] | ciphertext | tag: [u8; 16] |
+//! ```
+//!
+//! where `key_id` tells which key sealed it, so data written under an older
+//! key can still be read while the store moves over to a new one. What an
+//! encrypted store still shows is listed at `KvStoreBuilder::encryption_key`.
+use crate::{KvsError, Result};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::fmt;
+use std::path::Path;
+
+const KEY_ID_LEN: usize = 4;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+/// How many bytes sealing adds to a message.
+pub(crate) const OVERHEAD: usize = KEY_ID_LEN + NONCE_LEN + TAG_LEN;
+
+/// A 256-bit key to encrypt a `KvStore` with, see
+/// `KvStoreBuilder::encryption_key`.
+#[derive(Clone)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    pub fn new(bytes: [u8; 32]) -> EncryptionKey {
+        EncryptionKey(bytes)
+    }
+
+    /// Parses a key written as 64 hex digits.
+    pub fn from_hex(hex: &str) -> Result<EncryptionKey> {
+        let hex = hex.trim();
+        let invalid = || failure::err_msg("encryption key must be 64 hex digits");
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let mut key = [0u8; 32];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(EncryptionKey(key))
+    }
+
+    /// Reads a key file holding either the 32 raw key bytes or 64 hex
+    /// digits.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<EncryptionKey> {
+        let path = path.as_ref();
+        let contents = std::fs::read(path)?;
+        if let Ok(key) = <[u8; 32]>::try_from(&contents[..]) {
+            return Ok(EncryptionKey(key));
+        }
+        std::str::from_utf8(&contents)
+            .map_err(failure::Error::from)
+            .and_then(EncryptionKey::from_hex)
+            .map_err(|e| failure::format_err!("{}: {}", path.display(), e))
+    }
+
+    /// Reads a key written as 64 hex digits from the environment variable
+    /// `var`.
+    pub fn from_env(var: &str) -> Result<EncryptionKey> {
+        let hex = std::env::var(var).map_err(|e| failure::format_err!("{}: {}", var, e))?;
+        EncryptionKey::from_hex(&hex)
+    }
+
+    /// A new random key.
+    pub fn generate() -> EncryptionKey {
+        EncryptionKey(XChaCha20Poly1305::generate_key(&mut OsRng).into())
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+struct Key {
+    id: u32,
+    cipher: XChaCha20Poly1305,
+}
+
+impl Key {
+    fn new(key: &EncryptionKey) -> Key {
+        let cipher = XChaCha20Poly1305::new(&key.0.into());
+        // The tag of an empty message under a fixed nonce identifies the key
+        // without giving anything away about it
+        let tag = cipher
+            .encrypt(&XNonce::default(), &[][..])
+            .expect("sealing an empty message cannot fail");
+        Key {
+            id: u32::from_le_bytes(tag[..KEY_ID_LEN].try_into().unwrap()),
+            cipher,
+        }
+    }
+}
+
+/// The key new data is sealed with, if any, and the older keys that data
+/// may still be sealed with.
+pub(crate) struct Keyring {
+    current: Option<Key>,
+    previous: Vec<Key>,
+}
+
+impl Keyring {
+    pub(crate) fn new(current: Option<&EncryptionKey>, previous: &[EncryptionKey]) -> Keyring {
+        Keyring {
+            current: current.map(Key::new),
+            previous: previous.iter().map(Key::new).collect(),
+        }
+    }
+
+    /// Whether new data is sealed.
+    pub(crate) fn is_sealing(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Whether there is any key, current or previous.
+    pub(crate) fn has_keys(&self) -> bool {
+        self.current.is_some() || !self.previous.is_empty()
+    }
+
+    /// Whether `sealed` was sealed with the current key.
+    pub(crate) fn is_current(&self, sealed: &[u8]) -> bool {
+        self.current
+            .as_ref()
+            .is_some_and(|key| sealed.get(..KEY_ID_LEN) == Some(&key.id.to_le_bytes()[..]))
+    }
+
+    /// Encrypts `message` with the current key, authenticating `aad` along
+    /// with it.
+    pub(crate) fn seal(&self, aad: &[u8], message: &[u8]) -> Vec<u8> {
+        let key = self
+            .current
+            .as_ref()
+            .expect("sealing without an encryption key");
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        // Only fails for messages far larger than a record can be
+        let ciphertext = key
+            .cipher
+            .encrypt(&nonce, Payload { msg: message, aad })
+            .expect("message too large to encrypt");
+        let mut sealed = Vec::with_capacity(OVERHEAD + message.len());
+        sealed.extend_from_slice(&key.id.to_le_bytes());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Decrypts a message made by `seal`. Fails with `KvsError::WrongKey`
+    /// if none of the keys sealed it.
+    pub(crate) fn open(&self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < OVERHEAD {
+            return Err(failure::err_msg("sealed data is too short"));
+        }
+        let id = u32::from_le_bytes(sealed[..KEY_ID_LEN].try_into().unwrap());
+        let key = self
+            .current
+            .iter()
+            .chain(&self.previous)
+            .find(|key| key.id == id)
+            .ok_or(KvsError::WrongKey)?;
+        let nonce = XNonce::from_slice(&sealed[KEY_ID_LEN..KEY_ID_LEN + NONCE_LEN]);
+        let msg = &sealed[KEY_ID_LEN + NONCE_LEN..];
+        key.cipher
+            .decrypt(nonce, Payload { msg, aad })
+            .map_err(|_| failure::err_msg("sealed data failed authentication"))
+    }
+}
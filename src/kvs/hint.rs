@@ -0,0 +1,243 @@
+//! Hint files let `KvStore::open` rebuild the index for a compacted log
+//! without replaying it. `N.hint` sits next to `N.log` and lists where every
+//! key in that log lives, in key order:
+//!
+//! ```text
+//! | magic: "KVH\0" | version: u32 | max_seq: u64 | flags: u32 |
+//! | key_len: u32 | file_id: u64 | offset: u64 | length: u64 | expires_at: u64 | tombstone: u8 | key | ...
+//! | crc: u32 |
+//! ```
+//!
+//! `expires_at` is 0 for keys without an expiry time. `tombstone` is 1 if
+//! the key was removed: the `Rm` record at `offset` keeps it removed over
+//! an older log that still holds it, and `length` is the length of that
+//! record. With `FLAG_SEALED` set, every key is encrypted as described in
+//! `crypto` and `key_len` is the length of that. The entries still come in
+//! the order of the plain keys, so a sealed hint gives away how the keys of
+//! its log sort relative to each other, though not the keys themselves.
+//! The trailing `crc` covers the whole file. A hint can always be rebuilt
+//! from its log, so a missing, corrupt or outdated hint is simply ignored.
+//!
+//! Hints are written and read as streams, so they never have to fit in
+//! memory.
+use super::LogPointer;
+use super::crypto::Keyring;
+use crate::Result;
+use log::warn;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const MAGIC: [u8; 4] = *b"KVH\0";
+const VERSION: u32 = 6;
+const HEADER_LEN: u64 = 20;
+const ENTRY_HEADER_LEN: usize = 37;
+
+const FLAG_SEALED: u32 = 1;
+
+/// Writes a hint file atomically: the data goes to a temporary file that is
+/// synced and then renamed over the hint in `finish`.
+pub(super) struct HintWriter {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    file: BufWriter<File>,
+    crc: crc32fast::Hasher,
+    // keys are sealed if this has a current key
+    keys: Arc<Keyring>,
+}
+
+impl HintWriter {
+    pub(super) fn create(path: &Path, max_seq: u64, keys: Arc<Keyring>) -> Result<HintWriter> {
+        let tmp_path = path.with_extension("hint.tmp");
+        let flags = if keys.is_sealing() { FLAG_SEALED } else { 0 };
+        let mut writer = HintWriter {
+            path: path.to_path_buf(),
+            file: BufWriter::new(File::create(&tmp_path)?),
+            tmp_path,
+            crc: crc32fast::Hasher::new(),
+            keys,
+        };
+        writer.write(&MAGIC)?;
+        writer.write(&VERSION.to_le_bytes())?;
+        writer.write(&max_seq.to_le_bytes())?;
+        writer.write(&flags.to_le_bytes())?;
+        Ok(writer)
+    }
+
+    /// Adds an entry; they have to come in key order.
+    pub(super) fn push(&mut self, key: &[u8], ptr: &LogPointer) -> Result<()> {
+        self.push_entry(key, ptr, false)
+    }
+
+    /// Adds the tombstone of `key`, the `Rm` record at `ptr`, in key order
+    /// with the other entries.
+    pub(super) fn push_tombstone(&mut self, key: &[u8], ptr: &LogPointer) -> Result<()> {
+        self.push_entry(key, ptr, true)
+    }
+
+    fn push_entry(&mut self, key: &[u8], ptr: &LogPointer, tombstone: bool) -> Result<()> {
+        let sealed;
+        let key = if self.keys.is_sealing() {
+            sealed = self.keys.seal(&[], key);
+            &sealed[..]
+        } else {
+            key
+        };
+        let mut buf = Vec::with_capacity(ENTRY_HEADER_LEN + key.len());
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&ptr.file_id.to_le_bytes());
+        buf.extend_from_slice(&ptr.offset.to_le_bytes());
+        buf.extend_from_slice(&ptr.length.to_le_bytes());
+        buf.extend_from_slice(&ptr.expires_at.unwrap_or(0).to_le_bytes());
+        buf.push(tombstone as u8);
+        buf.extend_from_slice(key);
+        self.write(&buf)
+    }
+
+    pub(super) fn finish(mut self) -> Result<()> {
+        let crc = self.crc.clone().finalize();
+        self.file.write_all(&crc.to_le_bytes())?;
+        self.file
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        std::fs::rename(&self.tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<()> {
+        self.crc.update(buf);
+        self.file.write_all(buf)?;
+        Ok(())
+    }
+}
+
+/// A hint file that passed its checksum.
+pub(super) struct Hint {
+    /// Highest sequence number of any record in the log.
+    pub(super) max_seq: u64,
+    path: PathBuf,
+    len: u64,
+    sealed: bool,
+}
+
+impl Hint {
+    /// The entries of the hint, in key order. Sealed keys are opened with
+    /// `keys`.
+    pub(super) fn entries(&self, keys: &Arc<Keyring>) -> Result<HintEntries> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        std::io::copy(&mut (&mut reader).take(HEADER_LEN), &mut std::io::sink())?;
+        Ok(HintEntries {
+            reader: reader.take(self.len - HEADER_LEN - 4),
+            keys: self.sealed.then(|| Arc::clone(keys)),
+        })
+    }
+}
+
+pub(super) struct HintEntries {
+    reader: std::io::Take<BufReader<File>>,
+    keys: Option<Arc<Keyring>>,
+}
+
+/// What a hint lists for a key.
+pub(super) enum HintEntry {
+    /// The record holding the value of the key.
+    Value(LogPointer),
+    /// The `Rm` record that keeps the key removed.
+    Tombstone(LogPointer),
+}
+
+impl Iterator for HintEntries {
+    type Item = Result<(Vec<u8>, HintEntry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.reader.limit() == 0 {
+            return None;
+        }
+        let mut header = [0u8; ENTRY_HEADER_LEN];
+        let mut read = || -> Result<(Vec<u8>, HintEntry)> {
+            self.reader.read_exact(&mut header)?;
+            let u64_at = |pos: usize| u64::from_le_bytes(header[pos..pos + 8].try_into().unwrap());
+            let key_len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+            let ptr = LogPointer {
+                file_id: u64_at(4),
+                offset: u64_at(12),
+                length: u64_at(20),
+                expires_at: Some(u64_at(28)).filter(|&at| at != 0),
+                merged: 0,
+                merges: 0,
+            };
+            let mut key = vec![0u8; key_len];
+            self.reader.read_exact(&mut key)?;
+            if let Some(keys) = &self.keys {
+                key = keys.open(&[], &key)?;
+            }
+            let entry = match header[36] {
+                0 => HintEntry::Value(ptr),
+                _ => HintEntry::Tombstone(ptr),
+            };
+            Ok((key, entry))
+        };
+        let entry = read();
+        if entry.is_err() {
+            self.reader.set_limit(0);
+        }
+        Some(entry)
+    }
+}
+
+/// Checks the hint file at `path`, or returns `None` if there is no usable
+/// hint and the log has to be replayed instead.
+pub(super) fn read_hint(path: &Path) -> Result<Option<Hint>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let len = file.metadata()?.len();
+    let hint = verify(file, len)?.map(|(max_seq, flags)| Hint {
+        max_seq,
+        path: path.to_path_buf(),
+        len,
+        sealed: flags & FLAG_SEALED != 0,
+    });
+    if hint.is_none() {
+        warn!("{}: ignoring invalid hint file", path.display());
+    }
+    Ok(hint)
+}
+
+/// Checks the header and checksum of a hint and returns its `max_seq` and
+/// flags.
+fn verify(file: File, len: u64) -> Result<Option<(u64, u32)>> {
+    if len < HEADER_LEN + 4 {
+        return Ok(None);
+    }
+    let mut reader = BufReader::new(file);
+    let mut header = [0u8; HEADER_LEN as usize];
+    reader.read_exact(&mut header)?;
+    if header[..4] != MAGIC || header[4..8] != VERSION.to_le_bytes() {
+        return Ok(None);
+    }
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(&header);
+    let mut body = (&mut reader).take(len - HEADER_LEN - 4);
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = body.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        crc.update(&buf[..n]);
+    }
+    let mut stored = [0u8; 4];
+    reader.read_exact(&mut stored)?;
+    if crc.finalize() != u32::from_le_bytes(stored) {
+        return Ok(None);
+    }
+    Ok(Some((
+        u64::from_le_bytes(header[8..16].try_into().unwrap()),
+        u32::from_le_bytes(header[16..].try_into().unwrap()),
+    )))
+}
@@ -0,0 +1,546 @@
+//! The index of a `KvStore`: key -> location of the record holding its
+//! value, in key order.
+//!
+//! By default every entry is kept in a `BTreeMap`. With a memory budget,
+//! only the entries changed recently are, in the memtable; once that
+//! outgrows half the budget, it is merged with the sorted run on disk into
+//! a new run. A lookup that misses the memtable goes through a cache of
+//! recently read run entries, bounded by the other half of the budget, and
+//! otherwise reads the one block of the run that can hold the key. The
+//! first key of every block is kept in memory to find it.
+//!
+//! Runs are rebuilt from the logs on open and deleted once no index refers
+//! to them any more. A run file, `index-N.run`, is a plain sequence of
+//! entries in key order:
+//!
+//! ```text
+//! | key_len: u32 | file_id: u64 | offset: u64 | length: u64 | expires_at: u64 | merged: u64 | merges: u32 | key |
+//! ```
+//!
+//! In an encrypted store, the keys in runs are sealed with a random key
+//! that only lives as long as the index. The entries stay in the order of
+//! the plain keys, which a run therefore gives away.
+use super::LogPointer;
+use super::crypto::{EncryptionKey, Keyring};
+use super::log_file::read_exact_at;
+use crate::Result;
+use log::warn;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::iter::Peekable;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+const ENTRY_HEADER_LEN: usize = 48;
+// target size of a run block, the unit read from disk
+const BLOCK_SIZE: u64 = 4096;
+// rough in-memory size of an entry besides its key
+const ENTRY_OVERHEAD: usize = 64;
+
+#[derive(Clone)]
+pub(super) struct Index {
+    // key -> entry, or `None` for a key removed since the run was written
+    mem: BTreeMap<Vec<u8>, Option<LogPointer>>,
+    run: Option<Arc<Run>>,
+    // `None` for an index that keeps everything in memory
+    bounds: Option<Arc<Bounds>>,
+    mem_bytes: usize,
+}
+
+/// Where runs go and how much memory a bounded index may use.
+struct Bounds {
+    dir: PathBuf,
+    mem_limit: usize,
+    cache_limit: usize,
+    next_run: AtomicU64,
+    // seals the keys written to runs, if set
+    keys: Option<Arc<Keyring>>,
+}
+
+impl Index {
+    /// An index that keeps every entry in memory.
+    pub(super) fn unbounded() -> Index {
+        Index {
+            mem: BTreeMap::new(),
+            run: None,
+            bounds: None,
+            mem_bytes: 0,
+        }
+    }
+
+    /// An index using about `memory` bytes that keeps the rest in runs in
+    /// `dir`, with the keys encrypted if `seal` is set. Runs left behind by
+    /// an earlier open are deleted.
+    pub(super) fn bounded(dir: &Path, memory: usize, seal: bool) -> Result<Index> {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "run") {
+                std::fs::remove_file(&path)?;
+            }
+        }
+        Ok(Index {
+            bounds: Some(Arc::new(Bounds {
+                dir: dir.to_path_buf(),
+                mem_limit: memory / 2,
+                cache_limit: memory / 2,
+                next_run: AtomicU64::new(0),
+                keys: seal.then(|| Arc::new(Keyring::new(Some(&EncryptionKey::generate()), &[]))),
+            })),
+            ..Index::unbounded()
+        })
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.mem.is_empty() && self.run.is_none()
+    }
+
+    pub(super) fn get(&self, key: &[u8]) -> Result<Option<LogPointer>> {
+        match (self.mem.get(key), &self.run) {
+            (Some(slot), _) => Ok(*slot),
+            (None, Some(run)) => run.get(key),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Sets the entry of `key` and returns the previous one. Looking that
+    /// up may take a disk read; if it fails, it is only logged, as the
+    /// previous entry is only needed to count dead bytes.
+    pub(super) fn insert(&mut self, key: Vec<u8>, ptr: LogPointer) -> Option<LogPointer> {
+        if self.bounds.is_none() {
+            return self.mem.insert(key, Some(ptr)).flatten();
+        }
+        let old = self.previous(&key);
+        self.put(key, Some(ptr));
+        old
+    }
+
+    /// Removes the entry of `key` and returns it, see `insert`.
+    pub(super) fn remove(&mut self, key: &[u8]) -> Option<LogPointer> {
+        if self.run.is_none() {
+            return self.mem.remove(key).flatten();
+        }
+        let old = self.previous(key);
+        if old.is_some() {
+            self.put(key.to_vec(), None);
+        }
+        old
+    }
+
+    fn previous(&self, key: &[u8]) -> Option<LogPointer> {
+        self.get(key).unwrap_or_else(|e| {
+            warn!("index lookup failed: {}", e);
+            None
+        })
+    }
+
+    fn put(&mut self, key: Vec<u8>, slot: Option<LogPointer>) {
+        let len = key.len();
+        if self.mem.insert(key, slot).is_none() {
+            self.mem_bytes += len + ENTRY_OVERHEAD;
+        }
+    }
+
+    /// Entries with keys in `range`, in key order.
+    pub(super) fn range(&self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Range<'_> {
+        Range {
+            mem: self.mem.range(range.clone()).peekable(),
+            run: self
+                .run
+                .as_ref()
+                .map(|run| run.iter_from(range.0.as_ref()).peekable()),
+            end: range.1,
+        }
+    }
+
+    /// The entries held in memory: all of them unless the index is bounded.
+    pub(super) fn in_memory(&self) -> impl Iterator<Item = (&Vec<u8>, &LogPointer)> {
+        self.mem
+            .iter()
+            .filter_map(|(key, slot)| Some((key, slot.as_ref()?)))
+    }
+
+    /// Whether the memtable has outgrown its share of the budget and should
+    /// be merged into a new run, see `rewrite`.
+    pub(super) fn needs_spill(&self) -> bool {
+        self.bounds
+            .as_ref()
+            .is_some_and(|bounds| self.mem_bytes > bounds.mem_limit)
+    }
+
+    /// Fills an empty index with `entries`, which come in key order, without
+    /// going through the memtable.
+    pub(super) fn load_sorted(
+        &mut self,
+        entries: impl Iterator<Item = Result<(Vec<u8>, LogPointer)>>,
+    ) -> Result<()> {
+        debug_assert!(self.is_empty());
+        match &self.bounds {
+            None => {
+                for entry in entries {
+                    let (key, ptr) = entry?;
+                    self.mem.insert(key, Some(ptr));
+                }
+            }
+            Some(bounds) => {
+                let run = Run::write(bounds, entries)?;
+                self.run = run.map(Arc::new);
+            }
+        }
+        Ok(())
+    }
+
+    /// Passes every entry through `map` in key order, which returns the
+    /// new entry or `None` to drop it.
+    pub(super) fn rewrite_mut(
+        &mut self,
+        mut map: impl FnMut(&[u8], LogPointer) -> Result<Option<LogPointer>>,
+    ) -> Result<()> {
+        if self.bounds.is_some() {
+            let run = self.write_run(&mut map)?;
+            self.install(run);
+            return Ok(());
+        }
+        let mut result = Ok(());
+        self.mem.retain(|key, slot| {
+            let Some(ptr) = *slot else { return false };
+            if result.is_err() {
+                return true;
+            }
+            match map(key, ptr) {
+                Ok(new) => {
+                    *slot = new;
+                    new.is_some()
+                }
+                Err(e) => {
+                    result = Err(e);
+                    true
+                }
+            }
+        });
+        result
+    }
+
+    /// Writes the entries of a bounded index, as changed by `map`, into a
+    /// new run.
+    fn write_run(
+        &self,
+        mut map: impl FnMut(&[u8], LogPointer) -> Result<Option<LogPointer>>,
+    ) -> Result<Option<Run>> {
+        let bounds = self
+            .bounds
+            .as_ref()
+            .ok_or_else(|| failure::err_msg("index has no memory bound"))?;
+        let entries = self.range((Bound::Unbounded, Bound::Unbounded));
+        let mapped = entries.filter_map(|entry| {
+            let (key, ptr) = match entry {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e)),
+            };
+            map(&key, ptr).map(|new| Some((key, new?))).transpose()
+        });
+        Run::write(bounds, mapped)
+    }
+
+    fn install(&mut self, run: Option<Run>) {
+        self.mem.clear();
+        self.mem_bytes = 0;
+        self.run = run.map(Arc::new);
+    }
+}
+
+/// Like `Index::rewrite_mut` on the index behind `lock`. A bounded index
+/// is written to a new run under the read lock, so lookups go on in the
+/// meantime; the caller has to keep other changes out until it returns.
+pub(super) fn rewrite(
+    lock: &RwLock<Index>,
+    mut map: impl FnMut(&[u8], LogPointer) -> Result<Option<LogPointer>>,
+) -> Result<()> {
+    let run = {
+        let index = lock.read().unwrap();
+        match index.bounds {
+            Some(_) => Some(index.write_run(&mut map)?),
+            None => None,
+        }
+    };
+    let mut index = lock.write().unwrap();
+    match run {
+        Some(run) => index.install(run),
+        None => index.rewrite_mut(map)?,
+    }
+    Ok(())
+}
+
+/// Iterator over a range of an `Index`, see `Index::range`.
+pub(super) struct Range<'a> {
+    mem: Peekable<std::collections::btree_map::Range<'a, Vec<u8>, Option<LogPointer>>>,
+    run: Option<Peekable<RunIter<'a>>>,
+    end: Bound<Vec<u8>>,
+}
+
+impl Iterator for Range<'_> {
+    type Item = Result<(Vec<u8>, LogPointer)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let run_key = match self.run.as_mut().and_then(Peekable::peek) {
+                Some(Ok((key, _))) if before_end(key, &self.end) => Some(key),
+                Some(Ok(_)) | None => None,
+                Some(Err(_)) => return self.run.as_mut()?.next(),
+            };
+            let mem_key = self.mem.peek().map(|(key, _)| *key);
+            let (from_mem, from_run) = match (mem_key, run_key) {
+                (None, None) => return None,
+                (Some(_), None) => (true, false),
+                (None, Some(_)) => (false, true),
+                (Some(mem_key), Some(run_key)) => (mem_key <= run_key, mem_key >= run_key),
+            };
+            let run_entry = if from_run {
+                self.run.as_mut()?.next()
+            } else {
+                None
+            };
+            // The memtable is newer and wins over the run
+            if from_mem {
+                let (key, slot) = self.mem.next()?;
+                if let Some(ptr) = slot {
+                    return Some(Ok((key.clone(), *ptr)));
+                }
+                continue;
+            }
+            return run_entry;
+        }
+    }
+}
+
+fn before_end(key: &[u8], end: &Bound<Vec<u8>>) -> bool {
+    match end {
+        Bound::Included(end) => key <= &end[..],
+        Bound::Excluded(end) => key < &end[..],
+        Bound::Unbounded => true,
+    }
+}
+
+/// A sorted run of index entries on disk. The file is deleted when the
+/// run is dropped.
+struct Run {
+    path: PathBuf,
+    file: File,
+    // first key and offset of every block
+    blocks: Vec<(Vec<u8>, u64)>,
+    len: u64,
+    cache: Mutex<Cache>,
+    keys: Option<Arc<Keyring>>,
+}
+
+impl Run {
+    /// Writes `entries`, which come in key order, to a new run, or returns
+    /// `None` if there are none.
+    fn write(
+        bounds: &Bounds,
+        entries: impl Iterator<Item = Result<(Vec<u8>, LogPointer)>>,
+    ) -> Result<Option<Run>> {
+        let id = bounds.next_run.fetch_add(1, Ordering::SeqCst);
+        let path = bounds.dir.join(format!("index-{}.run", id));
+        let mut run = Run {
+            file: File::create(&path)?,
+            path,
+            blocks: Vec::new(),
+            len: 0,
+            cache: Mutex::new(Cache::new(bounds.cache_limit)),
+            keys: bounds.keys.clone(),
+        };
+        let mut writer = BufWriter::new(&run.file);
+        let mut block_start = None;
+        for entry in entries {
+            let (key, ptr) = entry?;
+            if block_start.is_none_or(|start| run.len - start >= BLOCK_SIZE) {
+                block_start = Some(run.len);
+                run.blocks.push((key.clone(), run.len));
+            }
+            let buf = match &run.keys {
+                Some(keys) => encode_entry(&keys.seal(&[], &key), &ptr),
+                None => encode_entry(&key, &ptr),
+            };
+            writer.write_all(&buf)?;
+            run.len += buf.len() as u64;
+        }
+        writer.flush()?;
+        drop(writer);
+        if run.blocks.is_empty() {
+            return Ok(None);
+        }
+        // Reopen for reading only; the data does not need to be durable
+        run.file = File::open(&run.path)?;
+        Ok(Some(run))
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<LogPointer>> {
+        if let Some(slot) = self.cache.lock().unwrap().get(key) {
+            return Ok(slot);
+        }
+        let block = self.blocks.partition_point(|(first, _)| &first[..] <= key);
+        let mut found = None;
+        if block > 0 {
+            for (entry_key, ptr) in self.read_block(block - 1)? {
+                if entry_key.as_slice() >= key {
+                    found = (entry_key == key).then_some(ptr);
+                    break;
+                }
+            }
+        }
+        self.cache.lock().unwrap().insert(key.to_vec(), found);
+        Ok(found)
+    }
+
+    fn read_block(&self, block: usize) -> Result<Vec<(Vec<u8>, LogPointer)>> {
+        let start = self.blocks[block].1;
+        let end = self.blocks.get(block + 1).map_or(self.len, |(_, at)| *at);
+        let mut buf = vec![0u8; (end - start) as usize];
+        read_exact_at(&self.file, &mut buf, start)?;
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos < buf.len() {
+            let (mut key, ptr, len) = decode_entry(&buf[pos..])
+                .ok_or_else(|| failure::format_err!("{}: invalid entry", self.path.display()))?;
+            if let Some(keys) = &self.keys {
+                key = keys.open(&[], &key)?;
+            }
+            entries.push((key, ptr));
+            pos += len;
+        }
+        Ok(entries)
+    }
+
+    /// Entries from the first key within `start` on, in key order.
+    fn iter_from(&self, start: Bound<&Vec<u8>>) -> RunIter<'_> {
+        let block = match start {
+            Bound::Included(key) | Bound::Excluded(key) => self
+                .blocks
+                .partition_point(|(first, _)| first <= key)
+                .saturating_sub(1),
+            Bound::Unbounded => 0,
+        };
+        RunIter {
+            run: self,
+            next_block: block,
+            entries: Vec::new().into_iter(),
+            start: start.cloned(),
+        }
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+struct RunIter<'a> {
+    run: &'a Run,
+    next_block: usize,
+    entries: std::vec::IntoIter<(Vec<u8>, LogPointer)>,
+    start: Bound<Vec<u8>>,
+}
+
+impl Iterator for RunIter<'_> {
+    type Item = Result<(Vec<u8>, LogPointer)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((key, ptr)) = self.entries.next() {
+                let after_start = match &self.start {
+                    Bound::Included(start) => key >= *start,
+                    Bound::Excluded(start) => key > *start,
+                    Bound::Unbounded => true,
+                };
+                if after_start {
+                    self.start = Bound::Unbounded;
+                    return Some(Ok((key, ptr)));
+                }
+                continue;
+            }
+            if self.next_block >= self.run.blocks.len() {
+                return None;
+            }
+            match self.run.read_block(self.next_block) {
+                Ok(entries) => self.entries = entries.into_iter(),
+                Err(e) => {
+                    self.next_block = self.run.blocks.len();
+                    return Some(Err(e));
+                }
+            }
+            self.next_block += 1;
+        }
+    }
+}
+
+/// Run entries looked up recently, kept in two generations: new entries go
+/// into the young one, which becomes the old one once it fills half the
+/// limit. Entries found in the old generation move back to the young one.
+struct Cache {
+    young: HashMap<Vec<u8>, Option<LogPointer>>,
+    old: HashMap<Vec<u8>, Option<LogPointer>>,
+    young_bytes: usize,
+    limit: usize,
+}
+
+impl Cache {
+    fn new(limit: usize) -> Cache {
+        Cache {
+            young: HashMap::new(),
+            old: HashMap::new(),
+            young_bytes: 0,
+            limit,
+        }
+    }
+
+    fn get(&mut self, key: &[u8]) -> Option<Option<LogPointer>> {
+        if let Some(slot) = self.young.get(key) {
+            return Some(*slot);
+        }
+        let (key, slot) = self.old.remove_entry(key)?;
+        self.insert(key, slot);
+        Some(slot)
+    }
+
+    fn insert(&mut self, key: Vec<u8>, slot: Option<LogPointer>) {
+        self.young_bytes += key.len() + ENTRY_OVERHEAD;
+        self.young.insert(key, slot);
+        if self.young_bytes > self.limit / 2 {
+            self.old = std::mem::take(&mut self.young);
+            self.young_bytes = 0;
+        }
+    }
+}
+
+fn encode_entry(key: &[u8], ptr: &LogPointer) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(ENTRY_HEADER_LEN + key.len());
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&ptr.file_id.to_le_bytes());
+    buf.extend_from_slice(&ptr.offset.to_le_bytes());
+    buf.extend_from_slice(&ptr.length.to_le_bytes());
+    buf.extend_from_slice(&ptr.expires_at.unwrap_or(0).to_le_bytes());
+    buf.extend_from_slice(&ptr.merged.to_le_bytes());
+    buf.extend_from_slice(&ptr.merges.to_le_bytes());
+    buf.extend_from_slice(key);
+    buf
+}
+
+/// Decodes the entry at the start of `buf` and returns it with its length.
+fn decode_entry(buf: &[u8]) -> Option<(Vec<u8>, LogPointer, usize)> {
+    let u64_at = |pos: usize| Some(u64::from_le_bytes(buf.get(pos..pos + 8)?.try_into().ok()?));
+    let key_len = u32::from_le_bytes(buf.get(..4)?.try_into().ok()?) as usize;
+    let ptr = LogPointer {
+        file_id: u64_at(4)?,
+        offset: u64_at(12)?,
+        length: u64_at(20)?,
+        expires_at: Some(u64_at(28)?).filter(|&at| at != 0),
+        merged: u64_at(36)?,
+        merges: u32::from_le_bytes(buf.get(44..48)?.try_into().ok()?),
+    };
+    let key = buf.get(ENTRY_HEADER_LEN..ENTRY_HEADER_LEN + key_len)?;
+    Some((key.to_vec(), ptr, ENTRY_HEADER_LEN + key_len))
+}
@@ -0,0 +1,88 @@
+use crate::Result;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A read handle on one `.log` file, shared between the index and any
+/// reads in flight through an `Arc`.
+///
+/// Reads are positional, so they need neither `&mut` nor a lock around the
+/// file cursor. A file replaced by compaction is only marked obsolete; it is
+/// deleted, together with its hint, once the last reference goes away.
+///
+/// Obsolete files are deleted in the order they were marked, oldest first,
+/// however long a read holds on to one of them: each keeps the one marked
+/// after it alive until it is gone itself. See `Compaction` for why.
+pub(super) struct LogFile {
+    path: PathBuf,
+    file: File,
+    obsolete: AtomicBool,
+    // the obsolete file to delete after this one
+    next_obsolete: Mutex<Option<Arc<LogFile>>>,
+}
+
+impl LogFile {
+    pub(super) fn open(path: &Path) -> Result<LogFile> {
+        Ok(LogFile {
+            path: path.to_path_buf(),
+            file: File::open(path)?,
+            obsolete: AtomicBool::new(false),
+            next_obsolete: Mutex::new(None),
+        })
+    }
+
+    /// Reads `len` bytes starting at `offset`.
+    pub(super) fn read_at(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; len as usize];
+        read_exact_at(&self.file, &mut buf, offset)?;
+        Ok(buf)
+    }
+
+    /// Deletes the file once nothing references it any more, but not
+    /// before `previous`, the file marked obsolete before it, if that is
+    /// still around.
+    pub(super) fn mark_obsolete(self: &Arc<Self>, previous: Option<Arc<LogFile>>) {
+        self.obsolete.store(true, Ordering::SeqCst);
+        if let Some(previous) = previous {
+            *previous.next_obsolete.lock().unwrap() = Some(Arc::clone(self));
+        }
+    }
+}
+
+impl Drop for LogFile {
+    fn drop(&mut self) {
+        if self.obsolete.load(Ordering::SeqCst) {
+            let _ = std::fs::remove_file(&self.path);
+            let _ = std::fs::remove_file(self.path.with_extension("hint"));
+            // The deletion has to be durable before the next one is made
+            if let Some(dir) = self.path.parent() {
+                let _ = super::sync_dir(dir);
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+pub(super) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+pub(super) fn read_exact_at(
+    file: &File,
+    mut buf: &mut [u8],
+    mut offset: u64,
+) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
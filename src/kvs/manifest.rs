@@ -0,0 +1,118 @@
+//! The `MANIFEST` of a store lists the log files it is made of, so that
+//! `KvStore::open` knows which of the files it finds are live:
+//!
+//! ```text
+//! | magic: "KVM\0" | version: u32 | count: u32 | file_id: u64 ... | crc: u32 |
+//! ```
+//!
+//! `crc` covers everything before it. The manifest is replaced whenever the
+//! set of files changes: before writes move to a new active file, and when
+//! a compaction is done, which commits it. The new list is written to
+//! `MANIFEST.tmp`, synced and renamed over the old one, so the manifest is
+//! always either the old or the new list.
+//!
+//! Log and hint files that are not listed were left behind by a crash: the
+//! output of a compaction that did not commit, or the files one that did
+//! replaced. Open deletes them. A store without a manifest, as written
+//! before there was one, is made of every log file in its directory.
+use super::sync_dir;
+use crate::Result;
+use log::info;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+const MAGIC: [u8; 4] = *b"KVM\0";
+const VERSION: u32 = 1;
+const NAME: &str = "MANIFEST";
+const TMP_NAME: &str = "MANIFEST.tmp";
+
+/// The ids of the log files listed in the manifest in `dir`, or `None` if
+/// there is none.
+pub(super) fn read(dir: &Path) -> Result<Option<BTreeSet<u64>>> {
+    let path = dir.join(NAME);
+    let mut buf = Vec::new();
+    match File::open(&path) {
+        Ok(mut file) => file.read_to_end(&mut buf)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let corrupt = || failure::format_err!("{}: manifest is corrupt", path.display());
+    if buf.len() < 16 || buf[..4] != MAGIC {
+        return Err(corrupt());
+    }
+    let (body, crc) = buf.split_at(buf.len() - 4);
+    if crc32fast::hash(body).to_le_bytes() != crc {
+        return Err(corrupt());
+    }
+    let version = u32::from_le_bytes(body[4..8].try_into().unwrap());
+    if version != VERSION {
+        return Err(failure::format_err!(
+            "{}: unsupported manifest version {} (expected {})",
+            path.display(),
+            version,
+            VERSION
+        ));
+    }
+    let count = u32::from_le_bytes(body[8..12].try_into().unwrap()) as usize;
+    let ids = &body[12..];
+    if ids.len() != count * 8 {
+        return Err(corrupt());
+    }
+    Ok(Some(
+        ids.chunks_exact(8)
+            .map(|id| u64::from_le_bytes(id.try_into().unwrap()))
+            .collect(),
+    ))
+}
+
+/// Replaces the manifest in `dir` with one listing `file_ids`.
+pub(super) fn write(dir: &Path, file_ids: &BTreeSet<u64>) -> Result<()> {
+    let mut buf = Vec::with_capacity(16 + file_ids.len() * 8);
+    buf.extend_from_slice(&MAGIC);
+    buf.extend_from_slice(&VERSION.to_le_bytes());
+    buf.extend_from_slice(&(file_ids.len() as u32).to_le_bytes());
+    for id in file_ids {
+        buf.extend_from_slice(&id.to_le_bytes());
+    }
+    buf.extend_from_slice(&crc32fast::hash(&buf).to_le_bytes());
+
+    let tmp_path = dir.join(TMP_NAME);
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, dir.join(NAME))?;
+    sync_dir(dir)
+}
+
+/// Deletes the log and hint files in `dir` that are not in `live`, and
+/// the temporary files of writes a crash cut short.
+pub(super) fn remove_orphans(dir: &Path, live: &BTreeSet<u64>) -> Result<()> {
+    let mut removed = false;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let (stem, tmp) = match name.strip_suffix(".tmp") {
+            Some(stem) => (stem, true),
+            None => (&name[..], false),
+        };
+        let orphan = match stem.split_once('.') {
+            Some((id, "log" | "hint")) => {
+                id.parse::<u64>().is_ok_and(|id| tmp || !live.contains(&id))
+            }
+            _ => tmp && stem == NAME,
+        };
+        if orphan {
+            info!("removing {}, which is not part of the store", name);
+            std::fs::remove_file(entry.path())?;
+            removed = true;
+        }
+    }
+    if removed {
+        sync_dir(dir)?;
+    }
+    Ok(())
+}
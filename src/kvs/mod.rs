@@ -0,0 +1,1928 @@
+use crate::CasOutcome;
+use crate::Cmd;
+use crate::Event;
+use crate::KvsEngine;
+use crate::KvsError;
+use crate::Op;
+use crate::Result;
+use crate::ScanIter;
+use crate::SyncPolicy;
+use crate::WriteBatch;
+use crate::background::Periodic;
+use crate::expiry::{self, now_millis};
+use crate::is_empty_range;
+use crate::merge::{self, MergeFn, MergeOperators};
+use crate::sync::GroupCommit;
+use crate::txn::Versions;
+use hint::HintEntry;
+use log::{error, info, warn};
+use log_file::LogFile;
+use record::{Codec, Entry, FORMAT_VERSION, FileFormat};
+use serde::Deserialize;
+use std::fs::{OpenOptions, TryLockError};
+use std::io::BufWriter;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::ops::{Bound, Range, RangeBounds};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::sync::Weak;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs::File,
+    io::{BufRead, BufReader},
+    path::PathBuf,
+};
+
+mod cache;
+mod crypto;
+mod hint;
+mod index;
+mod log_file;
+mod manifest;
+mod options;
+mod record;
+mod snapshot;
+mod transaction;
+mod tree;
+mod usage;
+mod watch;
+
+pub use cache::CacheStats;
+use cache::ValueCache;
+pub use crypto::EncryptionKey;
+use crypto::Keyring;
+use index::Index;
+pub use options::KvStoreBuilder;
+use options::Options;
+pub use snapshot::Snapshot;
+pub use transaction::Transaction;
+use tree::TreeSet;
+pub use usage::FileStats;
+use usage::Usage;
+pub use watch::Watcher;
+use watch::Watchers;
+
+/// Length of the header every log file starts with, which the byte counts
+/// of `FileStats` leave out.
+pub const LOG_HEADER_LEN: u64 = record::FILE_HEADER_LEN;
+
+// how many merge records a value is read through at most; a merge onto a
+// longer chain is resolved into a `Set` instead
+const MAX_MERGE_CHAIN: u32 = 32;
+
+#[derive(Clone, Copy, PartialEq)]
+struct LogPointer {
+    // byte position where the command starts
+    offset: u64,
+    // how mnay bytes the serialized command is
+    length: u64,
+    file_id: u64,
+    // when the key expires, in milliseconds since the Unix epoch
+    expires_at: Option<u64>,
+    // for a merge record, the bytes of the older records its value is
+    // merged onto; they are counted as dead, but read until compaction
+    // resolves the merge
+    merged: u64,
+    // for a merge record, how many merge records the value is read through,
+    // this one included
+    merges: u32,
+}
+
+impl LogPointer {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Bytes of every record the value is read from.
+    fn bytes(&self) -> u64 {
+        self.length + self.merged
+    }
+}
+
+/// # A implementatoin of Key Value Store
+///
+/// Reads only take the index and file map locks for reading and then use
+/// positional reads on a shared file handle, so they run in parallel with
+/// each other and with writes. Writes are serialized by the writer lock.
+
+#[derive(Clone)]
+pub struct KvStore {
+    inner: Arc<KvStoreInner>,
+    compactor: Arc<Compactor>,
+    // only held so that the sync and reaper threads stop with the last clone
+    _tasks: Arc<Vec<Periodic>>,
+    trees: TreeSet,
+}
+
+// Locks are always taken in the order `writer`, `store`, `files`.
+struct KvStoreInner {
+    // key -> location of its latest `Set` record, in key order
+    store: RwLock<Index>,
+    // every log file `store` may point into, including the active one
+    files: RwLock<HashMap<u64, Arc<LogFile>>>,
+    writer: Mutex<LogWriter>,
+    // handle on the active file for fsyncs made without the writer lock
+    active_file: Mutex<Option<Arc<File>>>,
+    // sequence number of the last record handed to the OS
+    written_seq: AtomicU64,
+    group_commit: GroupCommit,
+    // keys written while transactions are open; updated under `writer`
+    versions: Arc<Mutex<Versions>>,
+    operators: MergeOperators,
+    codec: Codec,
+    // `None` unless enabled with `KvStoreBuilder::value_cache`
+    cache: Option<ValueCache>,
+    // told about writes under `writer`, so they see them in order
+    watchers: Watchers,
+    // the file compaction retired last, which the files it retires next are
+    // only deleted after
+    last_obsolete: Mutex<Weak<LogFile>>,
+    // held for as long as the store is open, see `lock_dir`
+    _lock: Option<File>,
+    dir_path: PathBuf,
+    options: Options,
+}
+
+/// State only touched by writes and compaction.
+struct LogWriter {
+    current_file_id: u64,
+    // `None` when the store was opened read-only
+    writer: Option<BufWriter<File>>,
+    // offset the next record will be written at in the current file
+    writer_pos: u64,
+    // sequence number of the last record written
+    seq: u64,
+    // live and dead bytes in each file
+    usage: Usage,
+    // files the store was opened with that may have been written with
+    // other compression or encryption options, for compaction to re-encode
+    reencode: BTreeSet<u64>,
+    // whether a background compaction is running
+    compacting: bool,
+}
+
+impl KvStore {
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::builder(path).open()
+    }
+
+    /// Returns a builder to open the store at `path` with custom options.
+    pub fn builder(path: impl Into<PathBuf>) -> KvStoreBuilder {
+        KvStoreBuilder::new(path.into())
+    }
+
+    /// Counters of the value cache, all 0 if it is disabled.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.inner
+            .cache
+            .as_ref()
+            .map_or_else(CacheStats::default, ValueCache::stats)
+    }
+
+    /// Live and dead bytes of every log file, oldest first.
+    pub fn file_stats(&self) -> Vec<FileStats> {
+        let writer = self.inner.writer.lock().unwrap();
+        let mut file_ids: Vec<u64> = self.inner.files.read().unwrap().keys().copied().collect();
+        file_ids.sort_unstable();
+        file_ids
+            .into_iter()
+            .map(|file_id| writer.usage.stats(file_id))
+            .collect()
+    }
+
+    fn open_with(dir: PathBuf, options: Options) -> Result<KvStore> {
+        let lock = lock_dir(&dir, options.read_only)?;
+        let trees = TreeSet::new(dir.clone(), options.clone());
+        let file_ids: BTreeSet<u64> = match manifest::read(&dir)? {
+            Some(file_ids) => file_ids,
+            None => std::fs::read_dir(&dir)?
+                .filter_map(|entry| {
+                    let entry = entry.ok()?;
+                    let name = entry.file_name().into_string().ok()?;
+                    if name.ends_with(".log") {
+                        name.trim_end_matches(".log").parse::<u64>().ok()
+                    } else {
+                        None
+                    }
+                })
+                .collect(),
+        };
+        if !options.read_only {
+            manifest::remove_orphans(&dir, &file_ids)?;
+        }
+        if let Some(missing) = file_ids
+            .iter()
+            .map(|&fid| log_pathe(&dir, fid))
+            .find(|path| !path.exists())
+        {
+            return Err(failure::format_err!(
+                "{}: listed in the manifest but missing",
+                missing.display()
+            ));
+        }
+
+        let codec = Codec {
+            compress_from: options.compress_from,
+            keys: Arc::new(Keyring::new(
+                options.encryption_key.as_ref(),
+                &options.previous_keys,
+            )),
+        };
+        let mut index = match options.index_memory {
+            Some(memory) => Index::bounded(&dir, memory, codec.keys.is_sealing())?,
+            None => Index::unbounded(),
+        };
+        let mut files = HashMap::new();
+        let mut usage = Usage::default();
+        let mut seq: u64 = 0;
+        let now = now_millis();
+        let last_file_id = file_ids.last().copied().unwrap_or(0);
+        for &fid in &file_ids {
+            let fpath = log_pathe(&dir, fid);
+            // Compacted logs come with a hint holding their part of the index
+            if let Some(hint) = hint::read_hint(&hint_path(&dir, fid))? {
+                seq = seq.max(hint.max_seq);
+                usage.write(
+                    fid,
+                    std::fs::metadata(&fpath)?.len() - record::FILE_HEADER_LEN,
+                );
+                if index.is_empty() {
+                    // Entries that expired since are left out as dead, and so
+                    // are tombstones, which have nothing to remove yet
+                    let entries = hint.entries(&codec.keys)?.filter_map(|entry| match entry {
+                        Ok((_, HintEntry::Value(ptr))) if ptr.is_expired(now) => {
+                            usage.kill(ptr.file_id, ptr.length);
+                            None
+                        }
+                        Ok((_, HintEntry::Tombstone(ptr))) => {
+                            usage.kill(ptr.file_id, ptr.length);
+                            None
+                        }
+                        Ok((key, HintEntry::Value(ptr))) => Some(Ok((key, ptr))),
+                        Err(e) => Some(Err(e)),
+                    });
+                    index.load_sorted(entries)?;
+                } else {
+                    for entry in hint.entries(&codec.keys)? {
+                        match entry? {
+                            (key, HintEntry::Value(ptr)) => {
+                                replay_set(&mut index, &mut usage, key, ptr, now)
+                            }
+                            (key, HintEntry::Tombstone(ptr)) => {
+                                replay_tombstone(&mut index, &mut usage, &key, ptr)
+                            }
+                        }
+                        spill_if_needed(&mut index)?;
+                    }
+                }
+                files.insert(fid, Arc::new(LogFile::open(&fpath)?));
+                continue;
+            }
+
+            let mut replay_reader = BufReader::new(File::open(&fpath)?);
+            match record::detect_format(&mut replay_reader)? {
+                FileFormat::Empty => {
+                    files.insert(fid, Arc::new(LogFile::open(&fpath)?));
+                    continue;
+                }
+                FileFormat::LegacyJson if options.read_only => {
+                    return Err(failure::format_err!(
+                        "{}: legacy JSON log has to be migrated by a read-write open first",
+                        fpath.display()
+                    ));
+                }
+                FileFormat::LegacyJson => {
+                    migrate_legacy_log(&fpath, fid == last_file_id, &mut seq, &codec)?;
+                    replay_reader = BufReader::new(File::open(&fpath)?);
+                    replay_reader.seek(SeekFrom::Start(record::FILE_HEADER_LEN))?;
+                }
+                FileFormat::Binary(FORMAT_VERSION) => {}
+                FileFormat::Binary(version) => {
+                    return Err(failure::format_err!(
+                        "{}: unsupported log format version {} (expected {})",
+                        fpath.display(),
+                        version,
+                        FORMAT_VERSION
+                    ));
+                }
+            }
+
+            let file_len = replay_reader.get_ref().metadata()?.len();
+            let mut pos = record::FILE_HEADER_LEN;
+            // end of the last record that is not part of an unfinished batch
+            let mut committed = pos;
+            let mut batch = Vec::new();
+            loop {
+                let reason = match record::read_record(&mut replay_reader, file_len - pos, &codec)?
+                {
+                    Entry::Record {
+                        record,
+                        flags,
+                        length,
+                    } => {
+                        batch.push((record, pos, length));
+                        pos += length;
+                        if flags & record::FLAG_BATCH != 0 {
+                            continue;
+                        }
+                        for (record, offset, length) in batch.drain(..) {
+                            seq = seq.max(record.seq);
+                            usage.write(fid, length);
+                            let mut ptr = LogPointer {
+                                offset,
+                                length,
+                                file_id: fid,
+                                expires_at: record.expires_at,
+                                merged: 0,
+                                merges: 0,
+                            };
+                            match record.cmd {
+                                Cmd::Set { key, .. } => {
+                                    replay_set(&mut index, &mut usage, key, ptr, now);
+                                }
+                                Cmd::Merge { key, .. } => {
+                                    let old = index.get(&key)?;
+                                    ptr.merged = old.map_or(0, |old| old.bytes());
+                                    ptr.merges = old.map_or(0, |old| old.merges) + 1;
+                                    replay_set(&mut index, &mut usage, key, ptr, now);
+                                }
+                                Cmd::Rm { key } => {
+                                    replay_tombstone(&mut index, &mut usage, &key, ptr);
+                                }
+                            }
+                            spill_if_needed(&mut index)?;
+                        }
+                        committed = pos;
+                        continue;
+                    }
+                    Entry::End if batch.is_empty() => break,
+                    Entry::End => "incomplete write batch",
+                    Entry::Corrupt(reason) => reason,
+                };
+                // Only the file that was being written to when the store
+                // went down can be torn, anything else is real damage
+                if fid != last_file_id {
+                    return Err(failure::format_err!(
+                        "{}: {} at offset {} in a log file that is no longer written to",
+                        fpath.display(),
+                        reason,
+                        committed
+                    ));
+                }
+                if options.read_only {
+                    warn!(
+                        "{}: {} at offset {}, ignoring the rest of the file",
+                        fpath.display(),
+                        reason,
+                        committed
+                    );
+                } else {
+                    warn!(
+                        "{}: {} at offset {}, truncating {} bytes",
+                        fpath.display(),
+                        reason,
+                        committed,
+                        file_len - committed
+                    );
+                    truncate_log(&fpath, committed)?;
+                }
+                break;
+            }
+            files.insert(fid, Arc::new(LogFile::open(&fpath)?));
+        }
+        let reencode = if codec.compress_from.is_some() || codec.keys.has_keys() {
+            file_ids.clone()
+        } else {
+            BTreeSet::new()
+        };
+        let (current_file_id, writer) = if options.read_only {
+            (last_file_id, None)
+        } else {
+            let writer_path = log_pathe(&dir, last_file_id + 1);
+            let writer = new_log_file(&writer_path, options.write_buffer_size)?;
+            files.insert(last_file_id + 1, Arc::new(LogFile::open(&writer_path)?));
+            manifest::write(&dir, &files.keys().copied().collect())?;
+            (last_file_id + 1, Some(writer))
+        };
+        let active_file = match &writer {
+            Some(writer) => Some(Arc::new(writer.get_ref().try_clone()?)),
+            None => None,
+        };
+
+        let inner = KvStoreInner {
+            store: RwLock::new(index),
+            files: RwLock::new(files),
+            writer: Mutex::new(LogWriter {
+                current_file_id,
+                writer,
+                writer_pos: record::FILE_HEADER_LEN,
+                seq,
+                usage,
+                reencode,
+                compacting: false,
+            }),
+            active_file: Mutex::new(active_file),
+            written_seq: AtomicU64::new(seq),
+            group_commit: GroupCommit::new(),
+            versions: Arc::new(Mutex::new(Versions::default())),
+            watchers: Watchers::default(),
+            last_obsolete: Mutex::new(Weak::new()),
+            operators: MergeOperators::new(),
+            codec,
+            cache: options.cache_size.map(ValueCache::new),
+            _lock: lock,
+            dir_path: dir,
+            options,
+        };
+        let inner = Arc::new(inner);
+        let mut tasks = Vec::new();
+        if !inner.options.read_only {
+            if let SyncPolicy::Interval(interval) = inner.options.sync_policy {
+                let inner = Arc::clone(&inner);
+                tasks.push(Periodic::spawn(interval, move || {
+                    if let Err(e) = inner.sync() {
+                        error!("periodic sync failed: {}", e);
+                    }
+                }));
+            }
+            let reaper = Arc::clone(&inner);
+            tasks.push(Periodic::spawn(inner.options.reap_interval, move || {
+                reaper.reap()
+            }));
+        }
+        Ok(KvStore {
+            inner,
+            compactor: Arc::new(Compactor {
+                handle: Mutex::new(None),
+            }),
+            _tasks: Arc::new(tasks),
+            trees,
+        })
+    }
+}
+
+/// A compaction in progress. It is started under the writer lock, copies
+/// the live entries without holding any lock and is then applied under the
+/// locks again, so writes keep going to the active file in the meantime.
+///
+/// Only the files with enough dead bytes are compacted, the inputs; the
+/// others stay as they are. The output is split into files of about
+/// `max_file_size`, each holding a range of keys. Replay applies the files
+/// in id order and the output sorts after every input, so for replay to end
+/// up with the same index once the inputs are gone, the output holds for
+/// every key:
+///
+/// - its entry at the start of the compaction, if it was live then;
+/// - otherwise, a tombstone if an input removed the key or holds an expired
+///   entry of it, and a file older than that input stays. The older file
+///   may still hold the key, which would come back without the tombstone.
+///   Later compactions carry the tombstone over until no such file is left.
+///
+/// The compaction commits once the output and its hint are durable, by
+/// listing the output in place of the inputs in the manifest, and open
+/// deletes whatever a crash left of the other side; see `manifest`. The
+/// inputs are deleted oldest first after that, so that even a store
+/// without a manifest is left with either all the inputs next to a
+/// possibly partial output, or the newest of them next to the whole
+/// output. Both describe the store as it was when the compaction started,
+/// and the output replays after the inputs: removed keys stay removed.
+struct Compaction {
+    // ids reserved for the output, which sort between the inputs and the
+    // active file
+    file_ids: Range<u64>,
+    // every file frozen at the start, which merge chains may reach into
+    files: HashMap<u64, Arc<LogFile>>,
+    // the files being replaced by the output, a part of `files`
+    inputs: BTreeSet<u64>,
+    // the index at the start of the compaction
+    index: Index,
+    // size the output files are split at
+    max_file_size: u64,
+    seq: u64,
+    operators: MergeOperators,
+    codec: Codec,
+}
+
+impl KvStoreInner {
+    /// Appends `cmds` to the active file as one batch, which replay applies
+    /// either completely or not at all, and returns where each was written.
+    /// A batch is never split across files.
+    ///
+    /// A `Merge` is written as a merge record on top of the current value,
+    /// or as a `Set` of the merged value while a compaction runs, see
+    /// `merge_base`. `cmds` is updated to what was written.
+    fn append_batch(&self, writer: &mut LogWriter, cmds: &mut [Cmd]) -> Result<Vec<LogPointer>> {
+        if writer.writer.is_none() {
+            return Err(KvsError::ReadOnly.into());
+        }
+        let mut ptrs: Vec<LogPointer> = Vec::with_capacity(cmds.len());
+        let len = cmds.len();
+        for i in 0..len {
+            let flags = if i + 1 < len { record::FLAG_BATCH } else { 0 };
+            let base = match &cmds[i] {
+                Cmd::Merge { key, .. } => {
+                    // An earlier command of the batch may have written the key
+                    let batched = cmds[..i]
+                        .iter()
+                        .zip(&ptrs)
+                        .rev()
+                        .find(|(cmd, _)| cmd.key() == &key[..]);
+                    let current = match batched {
+                        Some((Cmd::Rm { .. }, _)) => None,
+                        Some((_, ptr)) => Some(*ptr),
+                        None => self.live_entry(key)?,
+                    };
+                    self.merge_base(writer, &mut cmds[i], current)?
+                }
+                _ => None,
+            };
+            let cmd = &cmds[i];
+            writer.seq += 1;
+            let encoded = self.codec.encode(cmd, base.as_ref(), writer.seq, flags);
+            let file = writer.writer.as_mut().ok_or(KvsError::ReadOnly)?;
+            file.write_all(&encoded)?;
+            let ptr = LogPointer {
+                offset: writer.writer_pos,
+                length: encoded.len() as u64,
+                file_id: writer.current_file_id,
+                expires_at: match cmd {
+                    Cmd::Set { expires_at, .. } => *expires_at,
+                    Cmd::Merge { .. } => base.and_then(|base| base.expires_at),
+                    Cmd::Rm { .. } => None,
+                },
+                merged: base.map_or(0, |base| base.bytes()),
+                merges: match cmd {
+                    Cmd::Merge { .. } => base.map_or(0, |base| base.merges) + 1,
+                    _ => 0,
+                },
+            };
+            writer.writer_pos += ptr.length;
+            writer.usage.write(ptr.file_id, ptr.length);
+            ptrs.push(ptr);
+        }
+        let file = writer.writer.as_mut().ok_or(KvsError::ReadOnly)?;
+        file.flush()?;
+        if self.options.sync_policy == SyncPolicy::Always {
+            file.get_ref().sync_data()?;
+        }
+        self.written_seq.store(writer.seq, Ordering::SeqCst);
+        if writer.writer_pos >= self.options.max_file_size {
+            self.rotate(writer, writer.current_file_id + 1)?;
+        }
+        Ok(ptrs)
+    }
+
+    /// Decides how the merge `cmd` is written on top of `current`, the entry
+    /// of its key, and returns the base its record points at.
+    ///
+    /// While a compaction runs, the files it copies are deleted once it is
+    /// done, and the compaction only knows about merge records written
+    /// before it started. So a merge onto a value in any but the active file
+    /// is resolved right away and `cmd` turned into a `Set`. So is a merge
+    /// onto a chain of `MAX_MERGE_CHAIN` merge records, to bound what a read
+    /// has to go through.
+    fn merge_base(
+        &self,
+        writer: &LogWriter,
+        cmd: &mut Cmd,
+        current: Option<LogPointer>,
+    ) -> Result<Option<LogPointer>> {
+        let Some(base) = current else {
+            return Ok(None);
+        };
+        let in_place = !writer.compacting || base.file_id == writer.current_file_id;
+        if in_place && base.merges < MAX_MERGE_CHAIN {
+            return Ok(Some(base));
+        }
+        let Cmd::Merge {
+            key,
+            operator,
+            operand,
+        } = cmd
+        else {
+            return Ok(Some(base));
+        };
+        let files = self.files.read().unwrap().clone();
+        let existing = read_value(&files, base, &self.operators, &self.codec)?;
+        let value = self
+            .operators
+            .apply(operator, key, existing.as_deref(), operand)?;
+        *cmd = Cmd::Set {
+            key: std::mem::take(key),
+            value,
+            expires_at: base.expires_at,
+        };
+        Ok(None)
+    }
+
+    /// Moves writes over to a new, empty log file with the given id.
+    ///
+    /// Unless the sync policy is `Never`, the old file is synced first, so a
+    /// sync of the active file covers every write made before it.
+    fn rotate(&self, writer: &mut LogWriter, file_id: u64) -> Result<()> {
+        if let Some(file) = writer.writer.as_mut() {
+            file.flush()?;
+            if self.options.sync_policy != SyncPolicy::Never {
+                file.get_ref().sync_data()?;
+            }
+        }
+        let path = log_pathe(&self.dir_path, file_id);
+        let new_writer = new_log_file(&path, self.options.write_buffer_size)?;
+        let new_file = Arc::new(LogFile::open(&path)?);
+        // Listed before anything is written to it, which would be lost
+        // otherwise
+        let mut file_ids: BTreeSet<u64> = self.files.read().unwrap().keys().copied().collect();
+        file_ids.insert(file_id);
+        manifest::write(&self.dir_path, &file_ids)?;
+        *self.active_file.lock().unwrap() = Some(Arc::new(new_writer.get_ref().try_clone()?));
+        writer.writer = Some(new_writer);
+        writer.writer_pos = record::FILE_HEADER_LEN;
+        writer.current_file_id = file_id;
+        self.files.write().unwrap().insert(file_id, new_file);
+        Ok(())
+    }
+
+    /// Syncs the active file, which makes every record up to the returned
+    /// sequence number durable.
+    fn sync(&self) -> Result<u64> {
+        // Read the sequence number before picking up the file: if a rotation
+        // happens in between, it already synced the older records.
+        let seq = self.written_seq.load(Ordering::SeqCst);
+        let file = self.active_file.lock().unwrap().clone();
+        if let Some(file) = file {
+            file.sync_data()?;
+        }
+        Ok(seq)
+    }
+
+    /// Waits until the record with sequence number `seq` is durable, if the
+    /// sync policy asks for it before a write is acknowledged.
+    fn wait_durable(&self, seq: u64) -> Result<()> {
+        if self.options.sync_policy != SyncPolicy::GroupCommit {
+            return Ok(());
+        }
+        self.group_commit.wait(
+            seq,
+            || self.written_seq.load(Ordering::SeqCst),
+            || self.sync().map(|_| ()),
+        )
+    }
+
+    fn needs_compaction(&self, writer: &LogWriter) -> bool {
+        let usage = writer.usage.total();
+        if usage.dead > self.options.compaction_threshold {
+            return true;
+        }
+        match self.options.dead_ratio {
+            Some(ratio) => usage.dead > 0 && usage.dead as f64 >= ratio * usage.bytes as f64,
+            None => false,
+        }
+    }
+
+    /// Looks up `key`. An expired key is reported as missing and, unless
+    /// the writer lock is busy, dropped from the index on the way.
+    ///
+    /// A value read while a write replaces it may still be put in the
+    /// cache, but under the location the index no longer points at, where
+    /// it is never found again and soon evicted.
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let (log_ptr, file) = {
+            let store = self.store.read().unwrap();
+            let log_ptr = match store.get(key)? {
+                None => return Ok(None),
+                Some(ptr) => ptr,
+            };
+            if log_ptr.is_expired(now_millis()) {
+                drop(store);
+                if let Ok(mut writer) = self.writer.try_lock() {
+                    self.drop_expired(&mut writer, key, log_ptr);
+                }
+                return Ok(None);
+            }
+            if let Some(value) = self.cache.as_ref().and_then(|cache| cache.get(&log_ptr)) {
+                return Ok(Some(value));
+            }
+            // Pin the files while the index lock is held, so compaction cannot
+            // move the entry and retire them in between. A merged value is
+            // read from several files.
+            let all_files = self.files.read().unwrap();
+            let files: HashMap<u64, Arc<LogFile>> = if log_ptr.merged > 0 {
+                all_files.clone()
+            } else {
+                let file = all_files
+                    .get(&log_ptr.file_id)
+                    .cloned()
+                    .ok_or_else(|| failure::err_msg("Log file not found"))?;
+                HashMap::from([(log_ptr.file_id, file)])
+            };
+            (log_ptr, files)
+        };
+        let value = read_value(&file, log_ptr, &self.operators, &self.codec)?;
+        if let (Some(cache), Some(value)) = (&self.cache, &value) {
+            cache.insert(&log_ptr, value.clone());
+        }
+        Ok(value)
+    }
+
+    /// Drops the cached value of a record the index stopped pointing at.
+    fn uncache(&self, ptr: &LogPointer) {
+        if let Some(cache) = &self.cache {
+            cache.remove(ptr);
+        }
+    }
+
+    /// The index entry of `key`, unless it is missing or expired.
+    fn live_entry(&self, key: &[u8]) -> Result<Option<LogPointer>> {
+        let ptr = self.store.read().unwrap().get(key)?;
+        Ok(ptr.filter(|ptr| !ptr.is_expired(now_millis())))
+    }
+
+    /// Removes `key` from the index if it still points at the expired
+    /// entry `ptr`. Nothing is logged: replay sees the same expiry time and
+    /// skips the entry again.
+    fn drop_expired(&self, writer: &mut LogWriter, key: &[u8], ptr: LogPointer) {
+        let mut store = self.store.write().unwrap();
+        if let Ok(Some(current)) = store.get(key)
+            && current == ptr
+        {
+            store.remove(key);
+            self.uncache(&ptr);
+            writer.usage.kill(ptr.file_id, ptr.length);
+            self.publish_expired(key);
+        }
+    }
+
+    /// Tells the watchers of `key` that it expired and is gone.
+    fn publish_expired(&self, key: &[u8]) {
+        self.watchers.publish(&Event {
+            key: key.to_vec(),
+            op: Op::Remove,
+            value: None,
+        });
+    }
+
+    /// The events for watchers of the keys of `cmds`, which were written
+    /// at `ptrs`. A merge is reported with the value it produced, which is
+    /// left out if it cannot be read.
+    fn events(&self, cmds: &[Cmd], ptrs: &[LogPointer]) -> Vec<Event> {
+        cmds.iter()
+            .zip(ptrs)
+            .filter(|(cmd, _)| self.watchers.is_watched(cmd.key()))
+            .map(|(cmd, ptr)| {
+                let (op, value) = match cmd {
+                    Cmd::Set { value, .. } => (Op::Set, Some(value.clone())),
+                    Cmd::Merge { .. } => {
+                        let files = self.files.read().unwrap();
+                        let value = read_value(&files, *ptr, &self.operators, &self.codec);
+                        (
+                            Op::Set,
+                            value.unwrap_or_else(|e| {
+                                error!("reading a merged value for watchers failed: {}", e);
+                                None
+                            }),
+                        )
+                    }
+                    Cmd::Rm { .. } => (Op::Remove, None),
+                };
+                Event {
+                    key: cmd.key().to_vec(),
+                    op,
+                    value,
+                }
+            })
+            .collect()
+    }
+
+    /// Drops every expired key from the index. Run by the reaper thread.
+    /// A memory-bounded index only has the entries it holds in memory
+    /// checked; the others are dropped once they are looked up or compacted.
+    fn reap(&self) {
+        let mut writer = self.writer.lock().unwrap();
+        let now = now_millis();
+        let expired: Vec<(Vec<u8>, LogPointer)> = self
+            .store
+            .read()
+            .unwrap()
+            .in_memory()
+            .filter(|(_, ptr)| ptr.is_expired(now))
+            .map(|(key, ptr)| (key.clone(), *ptr))
+            .collect();
+        for (key, ptr) in expired {
+            self.drop_expired(&mut writer, &key, ptr);
+        }
+    }
+
+    /// Collects the index entries picked by `select` and pins the files
+    /// they live in, so the values can be read after the locks are released
+    /// and the scan sees the store as it was when it started.
+    fn scan<F>(&self, select: F) -> Result<Scan>
+    where
+        F: FnOnce(&Index) -> Result<Vec<(Vec<u8>, LogPointer)>>,
+    {
+        let store = self.store.read().unwrap();
+        let entries = select(&store)?;
+        Ok(Scan::new(
+            entries,
+            &self.files.read().unwrap(),
+            &self.operators,
+            &self.codec,
+        ))
+    }
+
+    /// Copies the index and pins every file it points into.
+    /// Keys that are live now stay visible through the snapshot even after
+    /// they expire in the store.
+    fn snapshot(&self) -> Snapshot {
+        // The writer lock keeps `seq` in step with the index
+        let writer = self.writer.lock().unwrap();
+        let index = self.store.read().unwrap().clone();
+        let files = self.files.read().unwrap().clone();
+        Snapshot::new(
+            writer.seq,
+            index,
+            now_millis(),
+            files,
+            self.operators.clone(),
+            self.codec.clone(),
+        )
+    }
+
+    /// Starts a compaction once enough dead bytes have piled up, unless one
+    /// is already running.
+    fn maybe_start_compaction(&self, writer: &mut LogWriter) -> Result<Option<Compaction>> {
+        if writer.compacting || !self.needs_compaction(writer) {
+            return Ok(None);
+        }
+
+        // Step A: Pick the inputs: the files dead enough, the empty ones and
+        // those to re-encode
+        let ratio = self.options.file_dead_ratio;
+        let inputs: BTreeSet<u64> = self
+            .files
+            .read()
+            .unwrap()
+            .keys()
+            .copied()
+            .filter(|&id| {
+                let usage = writer.usage.file(id);
+                usage.bytes == 0
+                    || (usage.dead > 0 && usage.dead as f64 >= ratio * usage.bytes as f64)
+                    || writer.reencode.contains(&id)
+            })
+            .collect();
+        if inputs.is_empty() {
+            return Ok(None);
+        }
+
+        // Step B: Reserve file IDs for the output, which sort after every
+        // file it replaces and before the new active file. Every output file
+        // but the last is full, and the copies take no more room than the
+        // inputs unless merged values or encryption add to them, in which
+        // case the last file takes the rest.
+        let input_bytes: u64 = inputs.iter().map(|&id| writer.usage.file(id).bytes).sum();
+        let reserved = input_bytes / self.options.max_file_size.max(1) + 2;
+        let file_ids = writer.current_file_id + 1..writer.current_file_id + 1 + reserved;
+
+        // Step C: Freeze the existing files by moving writes to a new file
+        let index = self.store.read().unwrap().clone();
+        let files = self.files.read().unwrap().clone();
+        self.rotate(writer, file_ids.end)?;
+
+        writer.compacting = true;
+        Ok(Some(Compaction {
+            file_ids,
+            files,
+            inputs,
+            index,
+            max_file_size: self.options.max_file_size,
+            seq: writer.seq,
+            operators: self.operators.clone(),
+            codec: self.codec.clone(),
+        }))
+    }
+
+    fn finish_compaction(&self, compaction: Compaction, output: Vec<(u64, u64)>) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        let mut compacted = Vec::with_capacity(output.len());
+        let mut hints = Vec::with_capacity(output.len());
+        for &(file_id, _) in &output {
+            compacted.push(Arc::new(LogFile::open(&log_pathe(
+                &self.dir_path,
+                file_id,
+            ))?));
+            let hint = hint::read_hint(&hint_path(&self.dir_path, file_id))?
+                .ok_or_else(|| failure::err_msg("compaction hint is invalid"))?;
+            hints.push(hint.entries(&self.codec.keys)?);
+        }
+
+        // Step E: Commit the compaction by listing the output instead of the
+        // inputs in the manifest. A crash from here on leaves a store made
+        // of the output; before, the output is deleted on open.
+        let mut file_ids: BTreeSet<u64> = self
+            .files
+            .read()
+            .unwrap()
+            .keys()
+            .copied()
+            .filter(|id| !compaction.inputs.contains(id))
+            .collect();
+        file_ids.extend(output.iter().map(|&(file_id, _)| file_id));
+        manifest::write(&self.dir_path, &file_ids)?;
+        let mut files = self.files.write().unwrap();
+        for (&(file_id, bytes), file) in output.iter().zip(compacted) {
+            files.insert(file_id, file);
+            writer.usage.write(file_id, bytes);
+        }
+        drop(files);
+        let usage = &mut writer.usage;
+
+        // Step F: Point the index at the copies, as listed by the hints, and
+        // drop the entries of the inputs that expired instead. Entries
+        // written since the compaction started live in newer files and stay
+        // as they are; their copies are dead, as are those of keys removed
+        // since.
+        // The output files hold one range of keys after the other
+        let mut moved = hints.into_iter().flatten().peekable();
+        index::rewrite(&self.store, |key, ptr| {
+            let mut copy = None;
+            // Both come in key order
+            while let Some(entry) = moved.next_if(|entry| match entry {
+                Ok((moved_key, _)) => &moved_key[..] <= key,
+                Err(_) => true,
+            }) {
+                match entry? {
+                    (moved_key, HintEntry::Value(new_ptr)) if moved_key == key => {
+                        copy = Some(new_ptr)
+                    }
+                    (_, HintEntry::Value(new_ptr)) => usage.kill(new_ptr.file_id, new_ptr.length),
+                    (_, HintEntry::Tombstone(_)) => {}
+                }
+            }
+            if ptr.file_id >= compaction.file_ids.end {
+                if let Some(copy) = copy {
+                    usage.kill(copy.file_id, copy.length);
+                }
+                return Ok(Some(ptr));
+            }
+            if compaction.inputs.contains(&ptr.file_id) {
+                if copy.is_none() {
+                    self.publish_expired(key);
+                }
+                return Ok(copy);
+            }
+            // A merged value outside the inputs was resolved into a copy
+            match copy {
+                Some(copy) => {
+                    self.uncache(&ptr);
+                    usage.kill(ptr.file_id, ptr.length);
+                    Ok(Some(copy))
+                }
+                None => Ok(Some(ptr)),
+            }
+        })?;
+        for entry in moved {
+            if let (_, HintEntry::Value(new_ptr)) = entry? {
+                usage.kill(new_ptr.file_id, new_ptr.length);
+            }
+        }
+
+        // Step G: Retire the inputs. Reads that already picked one of them
+        // up keep it alive; it is deleted when the last of them finishes,
+        // after the older ones.
+        let mut files = self.files.write().unwrap();
+        let mut last_obsolete = self.last_obsolete.lock().unwrap();
+        for input in &compaction.inputs {
+            if let Some(file) = files.remove(input) {
+                file.mark_obsolete(last_obsolete.upgrade());
+                *last_obsolete = Arc::downgrade(&file);
+            }
+            writer.usage.remove(*input);
+            writer.reencode.remove(input);
+        }
+        drop(last_obsolete);
+        drop(files);
+        if let Some(cache) = &self.cache {
+            let inputs: Vec<u64> = compaction.inputs.iter().copied().collect();
+            cache.remove_files(&inputs);
+        }
+        writer.compacting = false;
+        Ok(())
+    }
+}
+
+impl Compaction {
+    /// Step D: Copies the live entries of the inputs into the output files,
+    /// together with the tombstones that are still needed, and writes their
+    /// hints, which list the new locations; entries that have expired are
+    /// left out. Returns the files with the size of the records in each.
+    fn copy_live_entries(&self, dir: &Path) -> Result<Vec<(u64, u64)>> {
+        let now = now_millis();
+        let mut tombstones = self.tombstones(dir, now)?.into_iter().peekable();
+        let mut output = CompactionOutput::create(
+            dir,
+            self.file_ids.clone(),
+            self.max_file_size,
+            self.seq,
+            &self.codec,
+        )?;
+
+        for entry in self.index.range((Bound::Unbounded, Bound::Unbounded)) {
+            let (key, log_ptr) = entry?;
+            // Both come in key order
+            while let Some((removed, seq)) = tombstones.next_if(|(removed, _)| removed <= &key) {
+                output.push_tombstone(removed, seq)?;
+            }
+            if log_ptr.is_expired(now) {
+                continue;
+            }
+            // A merge record may be merged onto a value in one of the inputs,
+            // so every merged value is copied, wherever it lives
+            if !self.inputs.contains(&log_ptr.file_id) && log_ptr.merged == 0 {
+                continue;
+            }
+            let file = self
+                .files
+                .get(&log_ptr.file_id)
+                .ok_or_else(|| failure::err_msg("reader not found"))?;
+            let mut buf = file.read_at(log_ptr.offset, log_ptr.length)?;
+            if log_ptr.merged > 0 {
+                // Merge records are resolved into a `Set` of their value
+                let seq = self.codec.decode(&buf)?.seq;
+                let value = read_value(&self.files, log_ptr, &self.operators, &self.codec)?
+                    .ok_or_else(|| failure::err_msg("index points at a remove record"))?;
+                let cmd = Cmd::Set {
+                    key: key.clone(),
+                    value,
+                    expires_at: log_ptr.expires_at,
+                };
+                buf = self.codec.encode(&cmd, None, seq, 0);
+            } else if let Some(reencoded) = self.codec.reencode(&buf)? {
+                // Compress and encrypt records written before that was
+                // turned on, or with an older key
+                buf = reencoded;
+            }
+            self.codec.clear_flags(&mut buf, record::FLAG_BATCH)?;
+            output.push(&key, &buf, log_ptr.expires_at)?;
+        }
+        for (removed, seq) in tombstones {
+            output.push_tombstone(removed, seq)?;
+        }
+        output.finish()
+    }
+
+    /// The keys that are not live but were removed by an input, or have an
+    /// expired entry in one, each with the sequence number of the last such
+    /// record. Only the inputs newer than a file that stays are read, and
+    /// only the keys such a file still holds a value of are kept, as no
+    /// other one needs a tombstone.
+    fn tombstones(&self, dir: &Path, now: u64) -> Result<BTreeMap<Vec<u8>, u64>> {
+        let mut tombstones = BTreeMap::new();
+        let oldest_kept = self
+            .files
+            .keys()
+            .filter(|id| !self.inputs.contains(id))
+            .min();
+        let Some(&oldest_kept) = oldest_kept else {
+            return Ok(tombstones);
+        };
+        for &file_id in self.inputs.range(oldest_kept..) {
+            let mut reader = BufReader::new(File::open(log_pathe(dir, file_id))?);
+            let file_len = reader.get_ref().metadata()?.len();
+            if file_len <= record::FILE_HEADER_LEN {
+                continue;
+            }
+            reader.seek(SeekFrom::Start(record::FILE_HEADER_LEN))?;
+            let mut pos = record::FILE_HEADER_LEN;
+            while let Entry::Record { record, length, .. } =
+                record::read_record(&mut reader, file_len - pos, &self.codec)?
+            {
+                pos += length;
+                let removed = match record.cmd {
+                    Cmd::Rm { .. } => true,
+                    _ => record.expires_at.is_some_and(|at| at <= now),
+                };
+                let key = record.cmd.key();
+                if removed && self.index.get(key)?.is_none_or(|ptr| ptr.is_expired(now)) {
+                    tombstones.insert(key.to_vec(), record.seq);
+                }
+            }
+        }
+        if !tombstones.is_empty() {
+            let held = self.held_by_kept(dir, &tombstones, now)?;
+            tombstones.retain(|key, _| held.contains(key));
+        }
+        Ok(tombstones)
+    }
+
+    /// Those of `keys` that the files older than the output and not among
+    /// the inputs leave with a value that has not expired, when replayed in
+    /// order.
+    fn held_by_kept(
+        &self,
+        dir: &Path,
+        keys: &BTreeMap<Vec<u8>, u64>,
+        now: u64,
+    ) -> Result<BTreeSet<Vec<u8>>> {
+        let mut kept: Vec<u64> = self
+            .files
+            .keys()
+            .copied()
+            .filter(|id| *id < self.file_ids.start && !self.inputs.contains(id))
+            .collect();
+        kept.sort_unstable();
+        let mut held = BTreeSet::new();
+        let mut update = |key: Vec<u8>, live: bool| {
+            if live {
+                held.insert(key);
+            } else {
+                held.remove(&key);
+            }
+        };
+        for file_id in kept {
+            if let Some(hint) = hint::read_hint(&hint_path(dir, file_id))? {
+                for entry in hint.entries(&self.codec.keys)? {
+                    match entry? {
+                        (key, _) if !keys.contains_key(&key) => {}
+                        (key, HintEntry::Value(ptr)) => update(key, !ptr.is_expired(now)),
+                        (key, HintEntry::Tombstone(_)) => update(key, false),
+                    }
+                }
+                continue;
+            }
+            let mut reader = BufReader::new(File::open(log_pathe(dir, file_id))?);
+            let file_len = reader.get_ref().metadata()?.len();
+            if file_len <= record::FILE_HEADER_LEN {
+                continue;
+            }
+            reader.seek(SeekFrom::Start(record::FILE_HEADER_LEN))?;
+            let mut pos = record::FILE_HEADER_LEN;
+            while let Entry::Record { record, length, .. } =
+                record::read_record(&mut reader, file_len - pos, &self.codec)?
+            {
+                pos += length;
+                let live = match record.cmd {
+                    Cmd::Rm { .. } => false,
+                    _ => record.expires_at.is_none_or(|at| at > now),
+                };
+                let key = record.cmd.key();
+                if keys.contains_key(key) {
+                    update(key.to_vec(), live);
+                }
+            }
+        }
+        Ok(held)
+    }
+}
+
+/// The log files a compaction writes. Each is started once the one before
+/// is full, until the ids reserved for them run out.
+struct CompactionOutput {
+    dir: PathBuf,
+    file_ids: Range<u64>,
+    max_size: u64,
+    seq: u64,
+    codec: Codec,
+    // the file being written
+    segment: Segment,
+    // the files written before, with the size of the records in each
+    done: Vec<(u64, u64)>,
+}
+
+impl CompactionOutput {
+    fn create(
+        dir: &Path,
+        file_ids: Range<u64>,
+        max_size: u64,
+        seq: u64,
+        codec: &Codec,
+    ) -> Result<CompactionOutput> {
+        Ok(CompactionOutput {
+            segment: Segment::create(dir, file_ids.start, seq, codec)?,
+            dir: dir.to_path_buf(),
+            file_ids,
+            max_size,
+            seq,
+            codec: codec.clone(),
+            done: Vec::new(),
+        })
+    }
+
+    /// Appends `buf`, the encoded record holding the value of `key`.
+    fn push(&mut self, key: &[u8], buf: &[u8], expires_at: Option<u64>) -> Result<()> {
+        self.next_segment_if_full()?;
+        let segment = &mut self.segment;
+        segment.log.write_all(buf)?;
+        let ptr = LogPointer {
+            offset: segment.offset,
+            length: buf.len() as u64,
+            file_id: segment.file_id,
+            expires_at,
+            merged: 0,
+            merges: 0,
+        };
+        segment.hint.push(key, &ptr)?;
+        segment.offset += ptr.length;
+        Ok(())
+    }
+
+    /// Appends the tombstone of `key`: an `Rm` record with the sequence
+    /// number of the removal it stands for.
+    fn push_tombstone(&mut self, key: Vec<u8>, seq: u64) -> Result<()> {
+        self.next_segment_if_full()?;
+        let segment = &mut self.segment;
+        let buf = self
+            .codec
+            .encode(&Cmd::Rm { key: key.clone() }, None, seq, 0);
+        segment.log.write_all(&buf)?;
+        let ptr = LogPointer {
+            offset: segment.offset,
+            length: buf.len() as u64,
+            file_id: segment.file_id,
+            expires_at: None,
+            merged: 0,
+            merges: 0,
+        };
+        segment.hint.push_tombstone(&key, &ptr)?;
+        segment.offset += ptr.length;
+        Ok(())
+    }
+
+    fn next_segment_if_full(&mut self) -> Result<()> {
+        let file_id = self.segment.file_id + 1;
+        if self.segment.offset < self.max_size || file_id >= self.file_ids.end {
+            return Ok(());
+        }
+        let next = Segment::create(&self.dir, file_id, self.seq, &self.codec)?;
+        let full = std::mem::replace(&mut self.segment, next);
+        self.done.push((full.file_id, full.finish()?));
+        Ok(())
+    }
+
+    /// Makes the files and their hints durable and returns them with the
+    /// size of the records in each.
+    fn finish(mut self) -> Result<Vec<(u64, u64)>> {
+        let file_id = self.segment.file_id;
+        self.done.push((file_id, self.segment.finish()?));
+        sync_dir(&self.dir)?;
+        Ok(self.done)
+    }
+}
+
+/// One file of the output of a compaction.
+struct Segment {
+    file_id: u64,
+    log: BufWriter<File>,
+    // The hint is written along, so open can skip replaying the new log
+    hint: hint::HintWriter,
+    // offset the next record will be written at
+    offset: u64,
+}
+
+impl Segment {
+    fn create(dir: &Path, file_id: u64, seq: u64, codec: &Codec) -> Result<Segment> {
+        Ok(Segment {
+            file_id,
+            log: new_log_file(&log_pathe(dir, file_id), 64 * 1024)?,
+            hint: hint::HintWriter::create(&hint_path(dir, file_id), seq, Arc::clone(&codec.keys))?,
+            offset: record::FILE_HEADER_LEN,
+        })
+    }
+
+    /// Syncs the file, writes its hint and returns the size of its records.
+    fn finish(mut self) -> Result<u64> {
+        self.log.flush()?;
+        self.log.get_ref().sync_all()?;
+        self.hint.finish()?;
+        Ok(self.offset - record::FILE_HEADER_LEN)
+    }
+}
+
+/// Runs a compaction on the background thread.
+fn compact(inner: &KvStoreInner, compaction: Compaction) {
+    let file_ids = compaction.file_ids.clone();
+    let result = compaction
+        .copy_live_entries(&inner.dir_path)
+        .and_then(|output| inner.finish_compaction(compaction, output));
+    if let Err(e) = result {
+        error!("compaction into {}.log failed: {}", file_ids.start, e);
+        let mut writer = inner.writer.lock().unwrap();
+        writer.compacting = false;
+        let files = inner.files.read().unwrap();
+        for file_id in file_ids.filter(|file_id| !files.contains_key(file_id)) {
+            let _ = remove_if_exists(&log_pathe(&inner.dir_path, file_id));
+            let _ = remove_if_exists(&hint_path(&inner.dir_path, file_id));
+        }
+    }
+}
+
+/// Owns the background compaction thread and is shared by every clone of a
+/// `KvStore`. Dropping the last clone waits for a running compaction.
+struct Compactor {
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Compactor {
+    fn spawn(&self, inner: Arc<KvStoreInner>, compaction: Compaction) {
+        let mut handle = self.handle.lock().unwrap();
+        if let Some(previous) = handle.take() {
+            let _ = previous.join();
+        }
+        *handle = Some(thread::spawn(move || compact(&inner, compaction)));
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.get_mut().unwrap().take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl KvStore {
+    /// Logs `cmds` as one batch, applies them to the index, tells the
+    /// watchers and releases the writer lock. Then starts a compaction if
+    /// one is due and waits until the write is durable, as far as the sync
+    /// policy asks for.
+    fn write(&self, mut writer: MutexGuard<'_, LogWriter>, mut cmds: Vec<Cmd>) -> Result<()> {
+        let ptrs = self.inner.append_batch(&mut writer, &mut cmds)?;
+        let events = self.inner.events(&cmds, &ptrs);
+        // Recorded before the index is updated, so a transaction that reads
+        // the new value also sees that it conflicts
+        self.inner
+            .versions
+            .lock()
+            .unwrap()
+            .record(cmds.iter().map(Cmd::key));
+        let mut store = self.inner.store.write().unwrap();
+        for (cmd, log_ptr) in cmds.into_iter().zip(ptrs) {
+            match cmd {
+                Cmd::Set { key, .. } | Cmd::Merge { key, .. } => {
+                    // A merge record is merged onto the old record, but that
+                    // is dead all the same: compaction resolves the merge
+                    // into a `Set` and drops it
+                    if let Some(old_ptr) = store.insert(key, log_ptr) {
+                        self.inner.uncache(&old_ptr);
+                        writer.usage.kill(old_ptr.file_id, old_ptr.length);
+                    }
+                }
+                Cmd::Rm { key } => {
+                    // The record stays live only while it keeps a value of
+                    // an older file removed, the same as on replay
+                    match store.remove(&key) {
+                        Some(old_ptr) => {
+                            self.inner.uncache(&old_ptr);
+                            writer.usage.kill(old_ptr.file_id, old_ptr.length);
+                            if old_ptr.file_id == log_ptr.file_id {
+                                writer.usage.kill(log_ptr.file_id, log_ptr.length);
+                            }
+                        }
+                        None => writer.usage.kill(log_ptr.file_id, log_ptr.length),
+                    }
+                }
+            }
+        }
+        let spill = store.needs_spill();
+        drop(store);
+        for event in &events {
+            self.inner.watchers.publish(event);
+        }
+        if spill {
+            // Failing only means the memtable keeps growing until the next try
+            if let Err(e) = index::rewrite(&self.inner.store, |_, ptr| Ok(Some(ptr))) {
+                error!("spilling the index to disk failed: {}", e);
+            }
+        }
+        let seq = writer.seq;
+        let compaction = self.inner.maybe_start_compaction(&mut writer)?;
+        drop(writer);
+        self.spawn_compaction(compaction);
+        self.inner.wait_durable(seq)
+    }
+
+    fn spawn_compaction(&self, compaction: Option<Compaction>) {
+        if let Some(compaction) = compaction {
+            self.compactor.spawn(Arc::clone(&self.inner), compaction);
+        }
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn set(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        let writer = self.inner.writer.lock().unwrap();
+        let cmd = Cmd::Set {
+            key: key.into(),
+            value: value.into(),
+            expires_at: None,
+        };
+        self.write(writer, vec![cmd])
+    }
+
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let writer = self.inner.writer.lock().unwrap();
+        let cmd = Cmd::Set {
+            key: key.into(),
+            value: value.into(),
+            expires_at: Some(expiry::expires_at(ttl)),
+        };
+        self.write(writer, vec![cmd])
+    }
+
+    fn ttl(&self, key: impl AsRef<[u8]>) -> Result<Option<Duration>> {
+        let ptr = self
+            .inner
+            .live_entry(key.as_ref())?
+            .ok_or_else(|| failure::err_msg("Key not found"))?;
+        Ok(ptr.expires_at.map(expiry::time_left))
+    }
+
+    fn persist(&self, key: impl AsRef<[u8]>) -> Result<()> {
+        let key = key.as_ref();
+        let writer = self.inner.writer.lock().unwrap();
+        let ptr = self
+            .inner
+            .live_entry(key)?
+            .ok_or_else(|| failure::err_msg("Key not found"))?;
+        if ptr.expires_at.is_none() {
+            return Ok(());
+        }
+        let value = self
+            .inner
+            .get(key)?
+            .ok_or_else(|| failure::err_msg("Key not found"))?;
+        let cmd = Cmd::Set {
+            key: key.to_vec(),
+            value,
+            expires_at: None,
+        };
+        self.write(writer, vec![cmd])
+    }
+
+    fn get_bytes(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        self.inner.get(key.as_ref())
+    }
+
+    fn remove(&self, key: impl AsRef<[u8]>) -> Result<()> {
+        let key = key.as_ref();
+        let writer = self.inner.writer.lock().unwrap();
+        if self.inner.live_entry(key)?.is_none() {
+            return Err(failure::err_msg("Key not found"));
+        }
+        let cmd = Cmd::Rm { key: key.to_vec() };
+        self.write(writer, vec![cmd])
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        batch.check_operators(&self.inner.operators)?;
+        let writer = self.inner.writer.lock().unwrap();
+        // Leave out removes of keys that will not exist by then, expired
+        // ones included
+        let cmds: Vec<Cmd> = {
+            let store = self.inner.store.read().unwrap();
+            let now = now_millis();
+            // If the lookup fails, the remove is simply kept
+            let is_live = |key: &[u8]| match store.get(key) {
+                Ok(ptr) => ptr.is_some_and(|ptr| !ptr.is_expired(now)),
+                Err(_) => true,
+            };
+            let mut exists = HashMap::new();
+            batch
+                .into_cmds()
+                .into_iter()
+                .filter(|cmd| match cmd {
+                    Cmd::Set { key, .. } | Cmd::Merge { key, .. } => {
+                        exists.insert(key.clone(), true);
+                        true
+                    }
+                    Cmd::Rm { key } => exists
+                        .insert(key.clone(), false)
+                        .unwrap_or_else(|| is_live(key)),
+                })
+                .collect()
+        };
+        if cmds.is_empty() {
+            return Ok(());
+        }
+        self.write(writer, cmds)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CasOutcome> {
+        let key = key.into();
+        let writer = self.inner.writer.lock().unwrap();
+        // No other write can get in between while the writer lock is held
+        let current = self.inner.get(&key)?;
+        if current != expected {
+            return Ok(CasOutcome::Conflict(current));
+        }
+        let cmd = match (new, current) {
+            (Some(value), _) => Cmd::Set {
+                expires_at: self.inner.live_entry(&key)?.and_then(|ptr| ptr.expires_at),
+                key,
+                value,
+            },
+            (None, Some(_)) => Cmd::Rm { key },
+            (None, None) => return Ok(CasOutcome::Swapped),
+        };
+        self.write(writer, vec![cmd])?;
+        Ok(CasOutcome::Swapped)
+    }
+
+    fn incr(&self, key: impl Into<Vec<u8>>, delta: i64) -> Result<i64> {
+        let key = key.into();
+        let writer = self.inner.writer.lock().unwrap();
+        let current = self.inner.get(&key)?;
+        let value = merge::add(current.as_deref(), delta)?;
+        let expires_at = self.inner.live_entry(&key)?.and_then(|ptr| ptr.expires_at);
+        let cmd = Cmd::Set {
+            key,
+            value: value.to_string().into_bytes(),
+            expires_at,
+        };
+        self.write(writer, vec![cmd])?;
+        Ok(value)
+    }
+
+    fn merge(
+        &self,
+        key: impl Into<Vec<u8>>,
+        operator: &str,
+        operand: impl Into<Vec<u8>>,
+    ) -> Result<()> {
+        self.inner.operators.check(operator)?;
+        let writer = self.inner.writer.lock().unwrap();
+        let cmd = Cmd::Merge {
+            key: key.into(),
+            operator: operator.to_owned(),
+            operand: operand.into(),
+        };
+        self.write(writer, vec![cmd])
+    }
+
+    fn register_merge_operator(
+        &self,
+        name: impl Into<String>,
+        operator: impl Fn(&[u8], Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync + 'static,
+    ) {
+        let operator: Arc<MergeFn> = Arc::new(operator);
+        self.inner.operators.register(name.into(), operator);
+        // Merged values read with the operator it replaces are out of date
+        if let Some(cache) = &self.inner.cache {
+            cache.clear();
+        }
+    }
+
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>, limit: Option<usize>) -> Result<ScanIter> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let now = now_millis();
+        let scan = self
+            .inner
+            .scan(|store| select_range(store, range, limit, now))?;
+        Ok(Box::new(scan))
+    }
+
+    fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Result<ScanIter> {
+        let now = now_millis();
+        let scan = self
+            .inner
+            .scan(|store| select_prefix(store, prefix.as_ref(), now))?;
+        Ok(Box::new(scan))
+    }
+
+    type Snapshot = Snapshot;
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        Ok(self.inner.snapshot())
+    }
+
+    type Transaction = Transaction;
+
+    fn begin(&self) -> Result<Transaction> {
+        Ok(Transaction::new(self.clone()))
+    }
+
+    type Watcher = Watcher;
+
+    fn watch(&self, prefix: impl Into<Vec<u8>>) -> Result<Watcher> {
+        Ok(self.inner.watchers.subscribe(prefix.into()))
+    }
+
+    /// A tree is a store of its own in a subdirectory, with its own logs
+    /// and compactions, opened with the same options. It also runs its own
+    /// background threads until the store is closed: one that drops expired
+    /// keys, one that syncs under `SyncPolicy::Interval`, and one while it
+    /// compacts, so every open tree of a writable store costs up to three
+    /// threads.
+    fn open_tree(&self, name: &str) -> Result<KvStore> {
+        self.trees
+            .open(name, true)?
+            .ok_or_else(|| failure::format_err!("tree {} does not exist", name))
+    }
+
+    fn open_existing_tree(&self, name: &str) -> Result<Option<KvStore>> {
+        self.trees.open(name, false)
+    }
+}
+
+/// Index entries with keys in `range` that are live at `now`, at most
+/// `limit` of them.
+fn select_range(
+    index: &Index,
+    range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+    limit: Option<usize>,
+    now: u64,
+) -> Result<Vec<(Vec<u8>, LogPointer)>> {
+    if is_empty_range(&range) {
+        return Ok(Vec::new());
+    }
+    index
+        .range(range)
+        .filter(|entry| !matches!(entry, Ok((_, ptr)) if ptr.is_expired(now)))
+        .take(limit.unwrap_or(usize::MAX))
+        .collect()
+}
+
+/// Index entries with keys starting with `prefix` that are live at `now`.
+fn select_prefix(index: &Index, prefix: &[u8], now: u64) -> Result<Vec<(Vec<u8>, LogPointer)>> {
+    index
+        .range((Bound::Included(prefix.to_vec()), Bound::Unbounded))
+        .take_while(|entry| !matches!(entry, Ok((key, _)) if !key.starts_with(prefix)))
+        .filter(|entry| !matches!(entry, Ok((_, ptr)) if ptr.is_expired(now)))
+        .collect()
+}
+
+/// The pairs picked by a scan, read from the log as the iterator advances.
+struct Scan {
+    entries: std::vec::IntoIter<(Vec<u8>, LogPointer)>,
+    files: HashMap<u64, Arc<LogFile>>,
+    operators: MergeOperators,
+    codec: Codec,
+}
+
+impl Scan {
+    /// Pins the files out of `all_files` that `entries` are read from.
+    fn new(
+        entries: Vec<(Vec<u8>, LogPointer)>,
+        all_files: &HashMap<u64, Arc<LogFile>>,
+        operators: &MergeOperators,
+        codec: &Codec,
+    ) -> Scan {
+        // A merged value may be read from any file
+        let files = if entries.iter().any(|(_, ptr)| ptr.merged > 0) {
+            all_files.clone()
+        } else {
+            entries
+                .iter()
+                .filter_map(|(_, ptr)| {
+                    let file = all_files.get(&ptr.file_id)?;
+                    Some((ptr.file_id, Arc::clone(file)))
+                })
+                .collect()
+        };
+        Scan {
+            entries: entries.into_iter(),
+            files,
+            operators: operators.clone(),
+            codec: codec.clone(),
+        }
+    }
+}
+
+impl Iterator for Scan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, ptr) = self.entries.next()?;
+        let value = read_value(&self.files, ptr, &self.operators, &self.codec);
+        match value {
+            Ok(Some(value)) => Some(Ok((key, value))),
+            Ok(None) => Some(Err(failure::err_msg("index points at a remove record"))),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Reads the value of the record at `ptr`. For a merge record, that means
+/// following the chain of merges back to the last `Set` and applying the
+/// operands to it in order.
+fn read_value(
+    files: &HashMap<u64, Arc<LogFile>>,
+    ptr: LogPointer,
+    operators: &MergeOperators,
+    codec: &Codec,
+) -> Result<Option<Vec<u8>>> {
+    let mut merges = Vec::new();
+    let mut next = Some(ptr);
+    let mut value = None;
+    while let Some(ptr) = next.take() {
+        let file = files
+            .get(&ptr.file_id)
+            .ok_or_else(|| failure::err_msg("Log file not found"))?;
+        let record = codec.decode(&file.read_at(ptr.offset, ptr.length)?)?;
+        match record.cmd {
+            Cmd::Set { value: set, .. } => value = Some(set),
+            Cmd::Rm { .. } => {}
+            Cmd::Merge {
+                key,
+                operator,
+                operand,
+            } => {
+                next = record.base;
+                merges.push((key, operator, operand));
+            }
+        }
+    }
+    for (key, operator, operand) in merges.into_iter().rev() {
+        value = Some(operators.apply(&operator, &key, value.as_deref(), &operand)?);
+    }
+    Ok(value)
+}
+
+/// Applies a `Set` or merge found during replay to the index being rebuilt
+/// and counts the bytes it made dead. One that has expired since replaces
+/// the previous value but is dropped itself. The record a merge record is
+/// merged onto is dead as well, as compaction resolves the merge.
+fn replay_set(index: &mut Index, usage: &mut Usage, key: Vec<u8>, ptr: LogPointer, now: u64) {
+    if ptr.is_expired(now) {
+        replay_remove(index, usage, &key);
+        usage.kill(ptr.file_id, ptr.length);
+    } else if let Some(old_ptr) = index.insert(key, ptr) {
+        usage.kill(old_ptr.file_id, old_ptr.length);
+    }
+}
+
+/// Applies a removal found during replay to the index being rebuilt and
+/// returns the entry it removed.
+fn replay_remove(index: &mut Index, usage: &mut Usage, key: &[u8]) -> Option<LogPointer> {
+    let old_ptr = index.remove(key)?;
+    usage.kill(old_ptr.file_id, old_ptr.length);
+    Some(old_ptr)
+}
+
+/// Applies the `Rm` record at `ptr` found during replay. It stays live only
+/// while it removes a value an older file holds, as that is when compaction
+/// carries it over.
+fn replay_tombstone(index: &mut Index, usage: &mut Usage, key: &[u8], ptr: LogPointer) {
+    match replay_remove(index, usage, key) {
+        Some(old_ptr) if old_ptr.file_id < ptr.file_id => {}
+        _ => usage.kill(ptr.file_id, ptr.length),
+    }
+}
+
+/// Moves the memtable of a memory-bounded index being rebuilt to disk once
+/// it outgrows the budget.
+fn spill_if_needed(index: &mut Index) -> Result<()> {
+    if index.needs_spill() {
+        index.rewrite_mut(|_, ptr| Ok(Some(ptr)))?;
+    }
+    Ok(())
+}
+
+fn log_pathe(dir: &Path, file_id: u64) -> PathBuf {
+    dir.join(format!("{}.log", file_id))
+}
+
+fn hint_path(dir: &Path, file_id: u64) -> PathBuf {
+    dir.join(format!("{}.hint", file_id))
+}
+
+/// Takes the advisory lock on the `LOCK` file in `dir`: exclusive for a
+/// read-write open, shared for a read-only one. The lock goes away with the
+/// returned file, also when the process dies. A read-only open changes
+/// nothing in the directory: without a `LOCK` file no writer has opened the
+/// store, and it takes no lock.
+fn lock_dir(dir: &Path, read_only: bool) -> Result<Option<File>> {
+    let path = dir.join("LOCK");
+    let file = if read_only {
+        match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+    } else {
+        OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?
+    };
+    let result = if read_only {
+        file.try_lock_shared()
+    } else {
+        file.try_lock()
+    };
+    match result {
+        Ok(()) => Ok(Some(file)),
+        Err(TryLockError::WouldBlock) => Err(KvsError::Locked(dir.to_path_buf()).into()),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Makes files created, renamed or deleted in `dir` durable.
+fn sync_dir(dir: &Path) -> Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// Creates a new log file and writes the file header into it.
+fn new_log_file(path: &Path, buffer_size: usize) -> Result<BufWriter<File>> {
+    let mut writer = BufWriter::with_capacity(
+        buffer_size,
+        OpenOptions::new().create(true).append(true).open(path)?,
+    );
+    writer.write_all(&record::file_header())?;
+    Ok(writer)
+}
+
+/// Cuts a log file back to `len` bytes, dropping a torn or corrupt tail.
+fn truncate_log(path: &Path, len: u64) -> Result<()> {
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(len)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// A command as written to the JSON logs, which only held strings.
+#[derive(Deserialize)]
+enum LegacyCmd {
+    Set { key: String, value: String },
+    Rm { key: String },
+}
+
+impl From<LegacyCmd> for Cmd {
+    fn from(cmd: LegacyCmd) -> Cmd {
+        match cmd {
+            LegacyCmd::Set { key, value } => Cmd::Set {
+                key: key.into_bytes(),
+                value: value.into_bytes(),
+                expires_at: None,
+            },
+            LegacyCmd::Rm { key } => Cmd::Rm {
+                key: key.into_bytes(),
+            },
+        }
+    }
+}
+
+/// Rewrites a log written in the old one-JSON-command-per-line format into
+/// the binary format. The file keeps its id, so replay order is unchanged.
+/// If it is the `newest` file, a torn last line is dropped, as on replay.
+fn migrate_legacy_log(path: &Path, newest: bool, seq: &mut u64, codec: &Codec) -> Result<()> {
+    let tmp_path = path.with_extension("log.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    writer.write_all(&record::file_header())?;
+    let mut lines = BufReader::new(File::open(path)?).split(b'\n');
+    while let Some(line) = lines.next() {
+        let line = line?;
+        if line.trim_ascii().is_empty() {
+            continue;
+        }
+        let cmd: LegacyCmd = match serde_json::from_slice(line.trim_ascii()) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                let last = lines.all(|line| line.is_ok_and(|line| line.trim_ascii().is_empty()));
+                if !newest || !last {
+                    return Err(failure::format_err!("{}: {}", path.display(), e));
+                }
+                warn!("{}: {} in the last line, ignoring it", path.display(), e);
+                break;
+            }
+        };
+        let cmd = Cmd::from(cmd);
+        *seq += 1;
+        writer.write_all(&codec.encode(&cmd, None, *seq, 0))?;
+    }
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    info!("migrated legacy JSON log {}", path.display());
+    Ok(())
+}
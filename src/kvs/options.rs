@@ -0,0 +1,188 @@
+use super::KvStore;
+use super::crypto::EncryptionKey;
+use crate::expiry::REAP_INTERVAL;
+use crate::{Result, SyncPolicy};
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Clone, Debug)]
+pub(super) struct Options {
+    pub(super) compaction_threshold: u64,
+    pub(super) dead_ratio: Option<f64>,
+    pub(super) file_dead_ratio: f64,
+    pub(super) max_file_size: u64,
+    pub(super) write_buffer_size: usize,
+    pub(super) sync_policy: SyncPolicy,
+    pub(super) read_only: bool,
+    pub(super) reap_interval: Duration,
+    pub(super) index_memory: Option<usize>,
+    pub(super) cache_size: Option<usize>,
+    pub(super) compress_from: Option<usize>,
+    pub(super) encryption_key: Option<EncryptionKey>,
+    pub(super) previous_keys: Vec<EncryptionKey>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            compaction_threshold: 1024 * 1024,
+            dead_ratio: None,
+            file_dead_ratio: 0.5,
+            max_file_size: 64 * 1024 * 1024,
+            write_buffer_size: 8 * 1024,
+            sync_policy: SyncPolicy::default(),
+            read_only: false,
+            reap_interval: REAP_INTERVAL,
+            index_memory: None,
+            cache_size: None,
+            compress_from: None,
+            encryption_key: None,
+            previous_keys: Vec::new(),
+        }
+    }
+}
+
+/// Opens a `KvStore` with non-default options, see `KvStore::builder`.
+pub struct KvStoreBuilder {
+    path: PathBuf,
+    options: Options,
+}
+
+impl KvStoreBuilder {
+    pub(super) fn new(path: PathBuf) -> Self {
+        KvStoreBuilder {
+            path,
+            options: Options::default(),
+        }
+    }
+
+    /// Compact once this many bytes in the log are dead. Defaults to 1 MiB.
+    pub fn compaction_threshold(mut self, bytes: u64) -> Self {
+        self.options.compaction_threshold = bytes;
+        self
+    }
+
+    /// Also compact once dead bytes make up at least `ratio` of the log,
+    /// however small it is. Must be in `(0, 1]`; off by default.
+    pub fn dead_ratio(mut self, ratio: f64) -> Self {
+        self.options.dead_ratio = Some(ratio);
+        self
+    }
+
+    /// Only compact the files at least `ratio` of which is dead, and leave
+    /// the others as they are. Must be in `[0, 1]`; defaults to 0.5.
+    pub fn file_dead_ratio(mut self, ratio: f64) -> Self {
+        self.options.file_dead_ratio = ratio;
+        self
+    }
+
+    /// Start a new active log file once the current one reaches this size,
+    /// and split the output of compaction into files of about this size.
+    /// Defaults to 64 MiB.
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.options.max_file_size = bytes;
+        self
+    }
+
+    /// Capacity of the buffer in front of the active log file. Defaults to
+    /// 8 KiB.
+    pub fn write_buffer_size(mut self, bytes: usize) -> Self {
+        self.options.write_buffer_size = bytes;
+        self
+    }
+
+    /// When writes are fsynced, see `SyncPolicy`. Defaults to
+    /// `SyncPolicy::Never`.
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.options.sync_policy = policy;
+        self
+    }
+
+    /// Open without creating or modifying any log file; writes fail. The
+    /// directory lock is taken shared, so several read-only opens can run
+    /// side by side, but not next to a read-write one.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.options.read_only = read_only;
+        self
+    }
+
+    /// How often a background thread drops expired keys from the index.
+    /// Expired keys are never returned either way. Defaults to 1 second.
+    pub fn reap_interval(mut self, interval: Duration) -> Self {
+        self.options.reap_interval = interval;
+        self
+    }
+
+    /// Bound the memory the index takes to about this many bytes. What does
+    /// not fit is kept in sorted run files next to the logs, so a lookup
+    /// costs at most one extra disk read, and recent changes are merged into
+    /// a new run as they pile up. By default the whole index is kept in
+    /// memory. Not supported together with `read_only`.
+    ///
+    /// The runs only last while the store is open: opening it deletes the
+    /// ones left behind and rebuilds the index by replaying every hint and
+    /// log, so the bound saves memory but not open time.
+    pub fn index_memory(mut self, bytes: usize) -> Self {
+        self.options.index_memory = Some(bytes);
+        self
+    }
+
+    /// Keep up to about this many bytes of recently read values in memory,
+    /// so reading a hot key again skips the log. Off by default; see
+    /// `KvStore::cache_stats` for how well it does.
+    pub fn value_cache(mut self, bytes: usize) -> Self {
+        self.options.cache_size = Some(bytes);
+        self
+    }
+
+    /// Compress values of at least `min_size` bytes in the log with LZ4.
+    /// Compaction also compresses the records written before this was
+    /// turned on. Logs may mix compressed and uncompressed records either
+    /// way. Off by default.
+    pub fn compress_values(mut self, min_size: usize) -> Self {
+        self.options.compress_from = Some(min_size);
+        self
+    }
+
+    /// Encrypt the logs, and the hint files that come with them, with
+    /// `key`. Records written before are read as they are and encrypted by
+    /// the next compaction. Opening an encrypted store without its key
+    /// fails with `KvsError::WrongKey`.
+    ///
+    /// Keys and values are hidden, but not everything about them: records
+    /// show their lengths, sequence numbers and expiry times, and hint files
+    /// and the runs of a memory-bounded index list the sealed keys in the
+    /// order of the plain ones, which gives away how the keys sort.
+    pub fn encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.options.encryption_key = Some(key);
+        self
+    }
+
+    /// An older key data may still be encrypted with. To rotate keys, open
+    /// with the new `encryption_key` and the old one here: reads use
+    /// whichever key a record was encrypted with, and compaction encrypts
+    /// everything it copies with the new one. Without an `encryption_key`,
+    /// compaction decrypts the logs instead.
+    pub fn previous_key(mut self, key: EncryptionKey) -> Self {
+        self.options.previous_keys.push(key);
+        self
+    }
+
+    pub fn open(self) -> Result<KvStore> {
+        if let Some(ratio) = self.options.dead_ratio
+            && !(ratio > 0.0 && ratio <= 1.0)
+        {
+            return Err(failure::format_err!("invalid dead ratio: {}", ratio));
+        }
+        let ratio = self.options.file_dead_ratio;
+        if !(0.0..=1.0).contains(&ratio) {
+            return Err(failure::format_err!("invalid file dead ratio: {}", ratio));
+        }
+        if self.options.read_only && self.options.index_memory.is_some() {
+            return Err(failure::err_msg(
+                "a memory-bounded index needs a read-write open",
+            ));
+        }
+        KvStore::open_with(self.path, self.options)
+    }
+}
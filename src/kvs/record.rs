@@ -0,0 +1,429 @@
+//! On-disk layout of the `.log` files written by `KvStore`.
+//!
+//! Every log file starts with an 8 byte file header, the `MAGIC` bytes
+//! followed by the format version, and is then a plain sequence of records:
+//!
+//! ```text
+//! | crc: u32 | key_len: u32 | value_len: u32 | kind: u8 | flags: u8 | seq: u64 | [expires_at: u64] | key | value |
+//! ```
+//!
+//! All integers are little-endian. `crc` is the CRC-32 of everything that
+//! follows it in the record, so a torn or corrupted write can be told apart
+//! from a valid record. `flags` is a bit set of per-record options:
+//!
+//! - `FLAG_BATCH`: the record is part of a write batch and more records of
+//!   the same batch follow it. The last record of a batch does not carry the
+//!   flag, so replay only applies a batch once that record is found.
+//! - `FLAG_EXPIRES`: a `Set` or `Merge` whose key expires. The header is
+//!   followed by `expires_at`, in milliseconds since the Unix epoch.
+//! - `FLAG_COMPRESSED`: the value of a `Set` is LZ4 compressed, prefixed
+//!   with its uncompressed length as a `u32`. `value_len` is the compressed
+//!   length.
+//! - `FLAG_ENCRYPTED`: `key` and `value` are sealed together as described
+//!   in `crypto`, which adds `crypto::OVERHEAD` bytes to the record.
+//!   `key_len` and `value_len` are the lengths before sealing. The rest of
+//!   the record but `crc` is authenticated along with them, so changing
+//!   any of it, flags included, makes the record fail to decrypt.
+//!
+//! The value of a `Merge` record starts with the location of the record
+//! holding the value it applies to, followed by the operand as packed by
+//! `merge::encode_operand`:
+//!
+//! ```text
+//! | base_file_id: u64 | base_offset: u64 | base_length: u64 | operand |
+//! ```
+//!
+//! A `base_length` of 0 means the key did not exist.
+use super::LogPointer;
+use super::crypto::{self, Keyring};
+use crate::merge;
+use crate::{Cmd, Result};
+use std::io::Read;
+use std::sync::Arc;
+
+pub(crate) const MAGIC: [u8; 4] = *b"KVS\0";
+pub(crate) const FORMAT_VERSION: u32 = 2;
+pub(crate) const FILE_HEADER_LEN: u64 = 8;
+pub(crate) const RECORD_HEADER_LEN: usize = 22;
+
+const KIND_SET: u8 = 1;
+const KIND_RM: u8 = 2;
+const KIND_MERGE: u8 = 3;
+const BASE_LEN: usize = 24;
+
+pub(crate) const FLAG_BATCH: u8 = 1;
+pub(crate) const FLAG_EXPIRES: u8 = 2;
+pub(crate) const FLAG_COMPRESSED: u8 = 4;
+pub(crate) const FLAG_ENCRYPTED: u8 = 8;
+
+/// What the first bytes of a log file say about its contents.
+pub(crate) enum FileFormat {
+    /// Nothing has been written yet, not even the file header.
+    Empty,
+    /// One JSON encoded `Cmd` per line, as written before the binary format.
+    LegacyJson,
+    /// The binary format, with the version taken from the file header.
+    Binary(u32),
+}
+
+pub(crate) fn file_header() -> [u8; FILE_HEADER_LEN as usize] {
+    let mut header = [0u8; FILE_HEADER_LEN as usize];
+    header[..4].copy_from_slice(&MAGIC);
+    header[4..].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header
+}
+
+/// Inspects the start of a log file. The reader is left positioned right
+/// after the file header for binary files. A file holding only part of the
+/// header was torn while being created and is reported as `Empty`.
+pub(crate) fn detect_format<R: Read>(reader: &mut R) -> Result<FileFormat> {
+    let mut header = [0u8; FILE_HEADER_LEN as usize];
+    let mut filled = 0;
+    while filled < header.len() {
+        let n = reader.read(&mut header[filled..])?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    if filled == 0 {
+        return Ok(FileFormat::Empty);
+    }
+    if header[0] == b'{' {
+        return Ok(FileFormat::LegacyJson);
+    }
+    if filled < header.len() && file_header().starts_with(&header[..filled]) {
+        return Ok(FileFormat::Empty);
+    }
+    if filled < header.len() || header[..4] != MAGIC {
+        return Err(failure::err_msg("unrecognized log file format"));
+    }
+    let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    Ok(FileFormat::Binary(version))
+}
+
+/// How records are encoded beyond their layout: which values are
+/// compressed and whether records are encrypted. Decoding reads records
+/// written under any codec, as long as it has the key they were encrypted
+/// with.
+#[derive(Clone)]
+pub(crate) struct Codec {
+    // values of at least this many bytes are compressed
+    pub(crate) compress_from: Option<usize>,
+    pub(crate) keys: Arc<Keyring>,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Codec {
+            compress_from: None,
+            keys: Arc::new(Keyring::new(None, &[])),
+        }
+    }
+}
+
+impl Codec {
+    /// Serializes `cmd` into a single record. `base` is where the value a
+    /// `Merge` applies to lives, and ignored for other commands; the merged
+    /// value expires together with it.
+    ///
+    /// The value of a `Set` of at least `compress_from` bytes is
+    /// compressed, unless that doesn't make it smaller.
+    pub(crate) fn encode(
+        &self,
+        cmd: &Cmd,
+        base: Option<&LogPointer>,
+        seq: u64,
+        flags: u8,
+    ) -> Vec<u8> {
+        let merge_value;
+        let compressed;
+        let mut flags = flags & !(FLAG_COMPRESSED | FLAG_ENCRYPTED);
+        let (kind, key, value, expires_at) = match cmd {
+            Cmd::Set {
+                key,
+                value,
+                expires_at,
+            } => match self.compress_from {
+                Some(min) if value.len() >= min => {
+                    compressed = lz4_flex::compress_prepend_size(value);
+                    if compressed.len() < value.len() {
+                        flags |= FLAG_COMPRESSED;
+                        (KIND_SET, &key[..], &compressed[..], *expires_at)
+                    } else {
+                        (KIND_SET, &key[..], &value[..], *expires_at)
+                    }
+                }
+                _ => (KIND_SET, &key[..], &value[..], *expires_at),
+            },
+            Cmd::Rm { key } => (KIND_RM, &key[..], &[][..], None),
+            Cmd::Merge {
+                key,
+                operator,
+                operand,
+            } => {
+                let mut value = Vec::with_capacity(BASE_LEN + 2 + operator.len() + operand.len());
+                let (file_id, offset, length) =
+                    base.map_or((0, 0, 0), |base| (base.file_id, base.offset, base.length));
+                value.extend_from_slice(&file_id.to_le_bytes());
+                value.extend_from_slice(&offset.to_le_bytes());
+                value.extend_from_slice(&length.to_le_bytes());
+                value.extend_from_slice(&merge::encode_operand(operator, operand));
+                merge_value = value;
+                (
+                    KIND_MERGE,
+                    &key[..],
+                    &merge_value[..],
+                    base.and_then(|base| base.expires_at),
+                )
+            }
+        };
+        flags = match expires_at {
+            Some(_) => flags | FLAG_EXPIRES,
+            None => flags & !FLAG_EXPIRES,
+        };
+        if self.keys.is_sealing() {
+            flags |= FLAG_ENCRYPTED;
+        }
+        let mut buf =
+            Vec::with_capacity(RECORD_HEADER_LEN + 8 + crypto::OVERHEAD + key.len() + value.len());
+        buf.extend_from_slice(&[0u8; 4]);
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        buf.push(kind);
+        buf.push(flags);
+        buf.extend_from_slice(&seq.to_le_bytes());
+        if let Some(expires_at) = expires_at {
+            buf.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        if flags & FLAG_ENCRYPTED != 0 {
+            let sealed = self.keys.seal(&aad(&buf), &[key, value].concat());
+            buf.extend_from_slice(&sealed);
+        } else {
+            buf.extend_from_slice(key);
+            buf.extend_from_slice(value);
+        }
+        let crc = crc32fast::hash(&buf[4..]);
+        buf[..4].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// Deserializes a record previously produced by `encode`.
+    pub(crate) fn decode(&self, buf: &[u8]) -> Result<Record> {
+        if buf.len() < RECORD_HEADER_LEN {
+            return Err(failure::err_msg("log record is too short"));
+        }
+        let header = Header::parse(&buf[..RECORD_HEADER_LEN]);
+        if buf.len() != header.record_len() {
+            return Err(failure::err_msg("log record length mismatch"));
+        }
+        if crc32fast::hash(&buf[4..]) != header.crc {
+            return Err(failure::err_msg("log record checksum mismatch"));
+        }
+        let body_start = header.body_start();
+        let expires_at = (header.flags & FLAG_EXPIRES != 0)
+            .then(|| u64::from_le_bytes(buf[RECORD_HEADER_LEN..body_start].try_into().unwrap()));
+        let opened;
+        let body = if header.flags & FLAG_ENCRYPTED != 0 {
+            opened = self
+                .keys
+                .open(&aad(&buf[..body_start]), &buf[body_start..])?;
+            &opened[..]
+        } else {
+            &buf[body_start..]
+        };
+        let (key, value) = body.split_at(header.key_len);
+        let key = key.to_vec();
+        let mut base = None;
+        let cmd = match header.kind {
+            KIND_SET if header.flags & FLAG_COMPRESSED != 0 => Cmd::Set {
+                key,
+                value: lz4_flex::decompress_size_prepended(value)
+                    .map_err(|e| failure::format_err!("invalid compressed value: {}", e))?,
+                expires_at,
+            },
+            KIND_SET => Cmd::Set {
+                key,
+                value: value.to_vec(),
+                expires_at,
+            },
+            KIND_RM => Cmd::Rm { key },
+            KIND_MERGE => {
+                if value.len() < BASE_LEN {
+                    return Err(failure::err_msg("merge record is too short"));
+                }
+                let u64_at = |i: usize| u64::from_le_bytes(value[i..i + 8].try_into().unwrap());
+                let length = u64_at(16);
+                if length != 0 {
+                    base = Some(LogPointer {
+                        file_id: u64_at(0),
+                        offset: u64_at(8),
+                        length,
+                        expires_at,
+                        merged: 0,
+                        merges: 0,
+                    });
+                }
+                let (operator, operand) = merge::decode_operand(&value[BASE_LEN..])?;
+                Cmd::Merge {
+                    key,
+                    operator: operator.to_owned(),
+                    operand: operand.to_vec(),
+                }
+            }
+            kind => return Err(failure::format_err!("unknown log record kind {}", kind)),
+        };
+        Ok(Record {
+            cmd,
+            seq: header.seq,
+            expires_at,
+            base,
+        })
+    }
+
+    /// Re-encodes a record written under another codec: compresses a
+    /// large value written uncompressed, and encrypts the record with the
+    /// current key or decrypts it if there is none. Returns `None` if the
+    /// record is left as it is.
+    pub(crate) fn reencode(&self, buf: &[u8]) -> Result<Option<Vec<u8>>> {
+        let header = Header::parse(&buf[..RECORD_HEADER_LEN]);
+        let encrypted = header.flags & FLAG_ENCRYPTED != 0;
+        let rekey = if self.keys.is_sealing() {
+            !encrypted || !self.keys.is_current(&buf[header.body_start()..])
+        } else {
+            encrypted
+        };
+        let compress = header.kind == KIND_SET
+            && header.flags & FLAG_COMPRESSED == 0
+            && self
+                .compress_from
+                .is_some_and(|min| header.value_len >= min);
+        if !rekey && !compress {
+            return Ok(None);
+        }
+        let record = self.decode(buf)?;
+        let encoded = self.encode(&record.cmd, record.base.as_ref(), record.seq, header.flags);
+        Ok((rekey || encoded.len() < buf.len()).then_some(encoded))
+    }
+
+    /// Clears `flags` on an encoded record, updating its checksum. Compaction
+    /// copies records on their own, so they must not claim to be followed by
+    /// the rest of a batch any more. An encrypted record is sealed again, as
+    /// its flags are authenticated.
+    pub(crate) fn clear_flags(&self, buf: &mut Vec<u8>, flags: u8) -> Result<()> {
+        if buf[13] & flags == 0 {
+            return Ok(());
+        }
+        if buf[13] & FLAG_ENCRYPTED != 0 {
+            let record = self.decode(buf)?;
+            *buf = self.encode(
+                &record.cmd,
+                record.base.as_ref(),
+                record.seq,
+                buf[13] & !flags,
+            );
+            return Ok(());
+        }
+        buf[13] &= !flags;
+        let crc = crc32fast::hash(&buf[4..]);
+        buf[..4].copy_from_slice(&crc.to_le_bytes());
+        Ok(())
+    }
+}
+
+/// What encryption authenticates besides the key and value: everything
+/// before them but the checksum, so the flags and `expires_at` as well.
+fn aad(head: &[u8]) -> Vec<u8> {
+    head[4..].to_vec()
+}
+
+/// A record as read back from the log.
+pub(crate) struct Record {
+    pub(crate) cmd: Cmd,
+    pub(crate) seq: u64,
+    /// When the key expires, in milliseconds since the Unix epoch.
+    pub(crate) expires_at: Option<u64>,
+    /// For a `Merge`, where the value it applies to lives.
+    pub(crate) base: Option<LogPointer>,
+}
+
+/// Result of reading one record during replay.
+pub(crate) enum Entry {
+    Record {
+        record: Record,
+        flags: u8,
+        length: u64,
+    },
+    /// Clean end of the file.
+    End,
+    /// The rest of the file does not hold a valid record: it was only
+    /// partially written or fails its checksum.
+    Corrupt(&'static str),
+}
+
+/// Reads the next record from `reader`, which has `remaining` bytes left
+/// before the end of the file.
+pub(crate) fn read_record<R: Read>(reader: &mut R, remaining: u64, codec: &Codec) -> Result<Entry> {
+    if remaining == 0 {
+        return Ok(Entry::End);
+    }
+    if remaining < RECORD_HEADER_LEN as u64 {
+        return Ok(Entry::Corrupt("truncated record header"));
+    }
+    let mut buf = vec![0u8; RECORD_HEADER_LEN];
+    reader.read_exact(&mut buf)?;
+    let header = Header::parse(&buf);
+    let length = header.record_len() as u64;
+    if length > remaining {
+        return Ok(Entry::Corrupt("truncated record"));
+    }
+    buf.resize(length as usize, 0);
+    reader.read_exact(&mut buf[RECORD_HEADER_LEN..])?;
+    if crc32fast::hash(&buf[4..]) != header.crc {
+        return Ok(Entry::Corrupt("checksum mismatch"));
+    }
+    Ok(Entry::Record {
+        record: codec.decode(&buf)?,
+        flags: header.flags,
+        length,
+    })
+}
+
+struct Header {
+    crc: u32,
+    key_len: usize,
+    value_len: usize,
+    kind: u8,
+    flags: u8,
+    seq: u64,
+}
+
+impl Header {
+    /// Length of the whole record this header starts.
+    fn record_len(&self) -> usize {
+        let sealing = if self.flags & FLAG_ENCRYPTED != 0 {
+            crypto::OVERHEAD
+        } else {
+            0
+        };
+        self.body_start() + sealing + self.key_len + self.value_len
+    }
+
+    /// Where the key and value, or their encryption, start.
+    fn body_start(&self) -> usize {
+        let expires_len = if self.flags & FLAG_EXPIRES != 0 { 8 } else { 0 };
+        RECORD_HEADER_LEN + expires_len
+    }
+
+    fn parse(buf: &[u8]) -> Header {
+        let u32_at = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap()) as usize;
+        Header {
+            crc: u32_at(0) as u32,
+            key_len: u32_at(4),
+            value_len: u32_at(8),
+            kind: buf[12],
+            flags: buf[13],
+            seq: u64::from_le_bytes(buf[14..22].try_into().unwrap()),
+        }
+    }
+}
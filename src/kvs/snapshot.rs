@@ -0,0 +1,82 @@
+use super::Index;
+use super::log_file::LogFile;
+use super::record::Codec;
+use super::{Scan, read_value, select_prefix, select_range};
+use crate::merge::MergeOperators;
+use crate::{KvsSnapshot, Result, ScanIter};
+use std::collections::HashMap;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+
+/// A read-only view of a `KvStore` as of one sequence number, returned by
+/// `KvsEngine::snapshot`.
+///
+/// It holds a copy of the index and a reference to every log file that copy
+/// points into. Compaction still replaces those files in the store, but
+/// they are only deleted once the snapshot is dropped.
+pub struct Snapshot {
+    seq: u64,
+    index: Index,
+    // keys live at this time stay visible, also after they expire
+    as_of: u64,
+    files: HashMap<u64, Arc<LogFile>>,
+    operators: MergeOperators,
+    codec: Codec,
+}
+
+impl Snapshot {
+    pub(super) fn new(
+        seq: u64,
+        index: Index,
+        as_of: u64,
+        files: HashMap<u64, Arc<LogFile>>,
+        operators: MergeOperators,
+        codec: Codec,
+    ) -> Snapshot {
+        Snapshot {
+            seq,
+            index,
+            as_of,
+            files,
+            operators,
+            codec,
+        }
+    }
+
+    /// Sequence number of the last write the snapshot sees.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+impl KvsSnapshot for Snapshot {
+    fn get_bytes(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        match self.index.get(key.as_ref())? {
+            Some(ptr) if !ptr.is_expired(self.as_of) => {
+                read_value(&self.files, ptr, &self.operators, &self.codec)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>, limit: Option<usize>) -> Result<ScanIter> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let entries = select_range(&self.index, range, limit, self.as_of)?;
+        Ok(Box::new(Scan::new(
+            entries,
+            &self.files,
+            &self.operators,
+            &self.codec,
+        )))
+    }
+
+    fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Result<ScanIter> {
+        let entries = select_prefix(&self.index, prefix.as_ref(), self.as_of)?;
+        Ok(Box::new(Scan::new(
+            entries,
+            &self.files,
+            &self.operators,
+            &self.codec,
+        )))
+    }
+}
@@ -0,0 +1,62 @@
+use super::KvStore;
+use crate::txn::TxnState;
+use crate::{KvsTransaction, Result};
+
+/// A transaction on a `KvStore`, returned by `KvsEngine::begin`.
+///
+/// Reads go to the store as it is, and fail early once a key has been
+/// written since the transaction began. The writes are buffered and logged
+/// as one batch on commit, so they are atomic like a `WriteBatch`.
+pub struct Transaction {
+    store: KvStore,
+    state: TxnState,
+}
+
+impl Transaction {
+    pub(super) fn new(store: KvStore) -> Transaction {
+        // The writer lock keeps the begin tick in step with the index
+        let writer = store.inner.writer.lock().unwrap();
+        let state = TxnState::begin(&store.inner.versions);
+        drop(writer);
+        Transaction { store, state }
+    }
+}
+
+impl KvsTransaction for Transaction {
+    fn get_bytes(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let key = key.as_ref();
+        if let Some(value) = self.state.cached(key) {
+            return Ok(value);
+        }
+        let value = self.store.inner.get(key)?;
+        self.state.observe(key, value)
+    }
+
+    fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        self.state.write(key.into(), Some(value.into()));
+        Ok(())
+    }
+
+    fn remove(&mut self, key: impl AsRef<[u8]>) -> Result<()> {
+        let key = key.as_ref();
+        if self.get_bytes(key)?.is_none() {
+            return Err(failure::err_msg("Key not found"));
+        }
+        self.state.write(key.to_vec(), None);
+        Ok(())
+    }
+
+    fn commit(mut self) -> Result<()> {
+        let writer = self.store.inner.writer.lock().unwrap();
+        // Every write records its keys under the writer lock, so nothing
+        // can get in between the check and the write below
+        self.state.check(&self.state.versions().lock().unwrap())?;
+        let cmds = self.state.finish();
+        if cmds.is_empty() {
+            return Ok(());
+        }
+        self.store.write(writer, cmds)
+    }
+
+    fn rollback(self) {}
+}
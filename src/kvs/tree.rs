@@ -0,0 +1,62 @@
+use super::KvStore;
+use super::options::Options;
+use crate::{Result, check_tree_name};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+
+/// The trees of a `KvStore`. Each is a store of its own in `trees/<name>`
+/// of the store's directory, opened with the same options, and stays open
+/// for as long as the store does.
+pub(super) struct Trees {
+    dir: PathBuf,
+    options: Options,
+    open: Mutex<HashMap<String, KvStore>>,
+}
+
+/// How a `KvStore` reaches the trees of its store: the store holds them,
+/// while a tree only points back at them, so that they can be dropped.
+#[derive(Clone)]
+pub(super) enum TreeSet {
+    Root(Arc<Trees>),
+    Tree(Weak<Trees>),
+}
+
+impl TreeSet {
+    pub(super) fn new(dir: PathBuf, options: Options) -> TreeSet {
+        TreeSet::Root(Arc::new(Trees {
+            dir: dir.join("trees"),
+            options,
+            open: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// Opens the tree `name`, or returns it if it is open already. A tree
+    /// that does not exist is created if `create` is set, and otherwise
+    /// `None` is returned.
+    pub(super) fn open(&self, name: &str, create: bool) -> Result<Option<KvStore>> {
+        check_tree_name(name)?;
+        let trees = match self {
+            TreeSet::Root(trees) => Arc::clone(trees),
+            TreeSet::Tree(trees) => trees
+                .upgrade()
+                .ok_or_else(|| failure::err_msg("the store of this tree is closed"))?,
+        };
+        let mut open = trees.open.lock().unwrap();
+        if let Some(tree) = open.get(name) {
+            return Ok(Some(tree.clone()));
+        }
+        let dir = trees.dir.join(name);
+        if create && !trees.options.read_only {
+            std::fs::create_dir_all(&dir)?;
+        } else if !dir.is_dir() {
+            return Ok(None);
+        }
+        let tree = KvStore {
+            trees: TreeSet::Tree(Arc::downgrade(&trees)),
+            ..KvStore::open_with(dir, trees.options.clone())?
+        };
+        open.insert(name.to_owned(), tree.clone());
+        Ok(Some(tree))
+    }
+}
@@ -0,0 +1,69 @@
+use std::collections::BTreeMap;
+
+/// How many bytes of records each log file holds, and how many of those
+/// are dead: overwritten, removed or expired, so that compacting the file
+/// would drop them.
+#[derive(Default)]
+pub(super) struct Usage {
+    files: BTreeMap<u64, FileUsage>,
+    total: FileUsage,
+}
+
+#[derive(Clone, Copy, Default)]
+pub(super) struct FileUsage {
+    pub(super) bytes: u64,
+    pub(super) dead: u64,
+}
+
+/// How much of a log file is dead, returned by `KvStore::file_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FileStats {
+    /// Id of the file, named `<file_id>.log` in the store's directory.
+    pub file_id: u64,
+    /// Bytes of records in the file.
+    pub bytes: u64,
+    /// Bytes of records that were overwritten, removed or have expired,
+    /// which compacting the file drops.
+    pub dead_bytes: u64,
+}
+
+impl Usage {
+    /// Counts `bytes` of records written to `file_id`.
+    pub(super) fn write(&mut self, file_id: u64, bytes: u64) {
+        self.files.entry(file_id).or_default().bytes += bytes;
+        self.total.bytes += bytes;
+    }
+
+    /// Counts `bytes` of records in `file_id` as dead.
+    pub(super) fn kill(&mut self, file_id: u64, bytes: u64) {
+        if let Some(file) = self.files.get_mut(&file_id) {
+            file.dead += bytes;
+            self.total.dead += bytes;
+        }
+    }
+
+    /// Forgets a file that was deleted.
+    pub(super) fn remove(&mut self, file_id: u64) {
+        if let Some(file) = self.files.remove(&file_id) {
+            self.total.bytes = self.total.bytes.saturating_sub(file.bytes);
+            self.total.dead = self.total.dead.saturating_sub(file.dead);
+        }
+    }
+
+    pub(super) fn file(&self, file_id: u64) -> FileUsage {
+        self.files.get(&file_id).copied().unwrap_or_default()
+    }
+
+    pub(super) fn stats(&self, file_id: u64) -> FileStats {
+        let usage = self.file(file_id);
+        FileStats {
+            file_id,
+            bytes: usage.bytes,
+            dead_bytes: usage.dead,
+        }
+    }
+
+    pub(super) fn total(&self) -> FileUsage {
+        self.total
+    }
+}
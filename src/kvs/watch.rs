@@ -0,0 +1,60 @@
+use crate::{Event, KvsWatcher};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::time::Duration;
+
+// events a watcher may fall behind by before it is ended
+const WATCH_BUFFER: usize = 1024;
+
+/// The changes to a prefix of a `KvStore`, returned by `KvsEngine::watch`.
+/// A watcher that falls `WATCH_BUFFER` events behind is ended: it returns
+/// the events it holds and then stops, as it does when the store closes.
+pub struct Watcher {
+    events: Receiver<Event>,
+}
+
+impl Iterator for Watcher {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        self.events.recv().ok()
+    }
+}
+
+impl KvsWatcher for Watcher {
+    fn next_timeout(&mut self, timeout: Duration) -> Result<Event, RecvTimeoutError> {
+        self.events.recv_timeout(timeout)
+    }
+}
+
+/// The open watchers of a store, each with the prefix it watches. Dropping
+/// this ends them all.
+#[derive(Default)]
+pub(super) struct Watchers {
+    subscribers: Mutex<Vec<(Vec<u8>, SyncSender<Event>)>>,
+}
+
+impl Watchers {
+    pub(super) fn subscribe(&self, prefix: Vec<u8>) -> Watcher {
+        let (sender, events) = mpsc::sync_channel(WATCH_BUFFER);
+        self.subscribers.lock().unwrap().push((prefix, sender));
+        Watcher { events }
+    }
+
+    /// Whether an event about `key` would reach anyone.
+    pub(super) fn is_watched(&self, key: &[u8]) -> bool {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(prefix, _)| key.starts_with(prefix))
+    }
+
+    /// Sends `event` to the watchers of its key, dropping those that are
+    /// gone or too far behind. A write never waits for a watcher.
+    pub(super) fn publish(&self, event: &Event) {
+        self.subscribers.lock().unwrap().retain(|(prefix, sender)| {
+            !event.key.starts_with(prefix) || sender.try_send(event.clone()).is_ok()
+        });
+    }
+}
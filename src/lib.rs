@@ -1,36 +1,453 @@
 // #![deny(missing_docs)]
+pub use error::KvsError;
 use failure::Error;
-pub use kvs::KvStore;
+pub use kvs::{CacheStats, EncryptionKey, FileStats, KvStore, KvStoreBuilder, LOG_HEADER_LEN};
+pub use merge::MergeFn;
 use serde::{Deserialize, Serialize};
+use std::ops::{Bound, RangeBounds};
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
 pub type Result<T> = std::result::Result<T, Error>;
+mod background;
+mod error;
+mod expiry;
 pub mod kvs;
+mod merge;
 pub mod sled_engine;
 pub mod thread_pool;
 pub use server::KvServer;
 pub use sled_engine::SledKvsEngine;
+pub use sync::SyncPolicy;
 mod server;
+mod sync;
+mod txn;
+mod wire;
 
 #[derive(Serialize, Deserialize)]
 pub enum Cmd {
-    Set { key: String, value: String },
-    Rm { key: String },
+    Set {
+        #[serde(with = "wire")]
+        key: Vec<u8>,
+        #[serde(with = "wire")]
+        value: Vec<u8>,
+        /// When the key expires, in milliseconds since the Unix epoch.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+    },
+    Rm {
+        #[serde(with = "wire")]
+        key: Vec<u8>,
+    },
+    /// Merges `operand` into the value of `key` with the merge operator
+    /// registered as `operator`.
+    Merge {
+        #[serde(with = "wire")]
+        key: Vec<u8>,
+        operator: String,
+        #[serde(with = "wire")]
+        operand: Vec<u8>,
+    },
 }
 
+impl Cmd {
+    pub(crate) fn key(&self) -> &[u8] {
+        match self {
+            Cmd::Set { key, .. } | Cmd::Rm { key } | Cmd::Merge { key, .. } => key,
+        }
+    }
+}
+
+/// Sets and removes applied by `KvsEngine::write_batch` as one atomic
+/// write: after a crash either all of them are visible or none is.
+#[derive(Default, Serialize, Deserialize)]
+pub struct WriteBatch {
+    cmds: Vec<Cmd>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.cmds.push(Cmd::Set {
+            key: key.into(),
+            value: value.into(),
+            expires_at: None,
+        });
+        self
+    }
+
+    /// Removes `key`. Unlike `KvsEngine::remove`, removing a key that does
+    /// not exist is not an error.
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.cmds.push(Cmd::Rm { key: key.into() });
+        self
+    }
+
+    /// Merges `operand` into `key`, see `KvsEngine::merge`.
+    pub fn merge(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        operator: impl Into<String>,
+        operand: impl Into<Vec<u8>>,
+    ) -> &mut Self {
+        self.cmds.push(Cmd::Merge {
+            key: key.into(),
+            operator: operator.into(),
+            operand: operand.into(),
+        });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.cmds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cmds.is_empty()
+    }
+
+    pub(crate) fn into_cmds(self) -> Vec<Cmd> {
+        self.cmds
+    }
+
+    /// Fails if a merge in the batch uses an unknown operator.
+    pub(crate) fn check_operators(&self, operators: &merge::MergeOperators) -> Result<()> {
+        for cmd in &self.cmds {
+            if let Cmd::Merge { operator, .. } = cmd {
+                operators.check(operator)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A request to a `KvServer`, sent as JSON. Keys and values are JSON
+/// strings where they are valid UTF-8, and arrays of bytes otherwise.
 #[derive(Serialize, Deserialize)]
 pub enum Request {
-    Set { key: String, value: String },
-    Get { key: String },
-    Remove { key: String },
+    Set {
+        #[serde(with = "wire")]
+        key: Vec<u8>,
+        #[serde(with = "wire")]
+        value: Vec<u8>,
+    },
+    Get {
+        #[serde(with = "wire")]
+        key: Vec<u8>,
+    },
+    Remove {
+        #[serde(with = "wire")]
+        key: Vec<u8>,
+    },
+    /// Keys from `start` (inclusive) to `end` (exclusive), unbounded where
+    /// `None`.
+    Scan {
+        #[serde(with = "wire::option")]
+        start: Option<Vec<u8>>,
+        #[serde(with = "wire::option")]
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    },
+    ScanPrefix {
+        #[serde(with = "wire")]
+        prefix: Vec<u8>,
+        limit: Option<usize>,
+    },
+    Batch(WriteBatch),
+    SetWithTtl {
+        #[serde(with = "wire")]
+        key: Vec<u8>,
+        #[serde(with = "wire")]
+        value: Vec<u8>,
+        ttl_ms: u64,
+    },
+    Ttl {
+        #[serde(with = "wire")]
+        key: Vec<u8>,
+    },
+    Persist {
+        #[serde(with = "wire")]
+        key: Vec<u8>,
+    },
+    CompareAndSwap {
+        #[serde(with = "wire")]
+        key: Vec<u8>,
+        #[serde(with = "wire::option")]
+        expected: Option<Vec<u8>>,
+        #[serde(with = "wire::option")]
+        new: Option<Vec<u8>>,
+    },
+    Incr {
+        #[serde(with = "wire")]
+        key: Vec<u8>,
+        delta: i64,
+    },
+    Append {
+        #[serde(with = "wire")]
+        key: Vec<u8>,
+        #[serde(with = "wire")]
+        suffix: Vec<u8>,
+    },
+    Merge {
+        #[serde(with = "wire")]
+        key: Vec<u8>,
+        operator: String,
+        #[serde(with = "wire")]
+        operand: Vec<u8>,
+    },
+    /// Starts a transaction. It belongs to the connection and is rolled back
+    /// when the connection closes.
+    Begin,
+    TxnGet {
+        txn: u64,
+        #[serde(with = "wire")]
+        key: Vec<u8>,
+    },
+    TxnSet {
+        txn: u64,
+        #[serde(with = "wire")]
+        key: Vec<u8>,
+        #[serde(with = "wire")]
+        value: Vec<u8>,
+    },
+    TxnRemove {
+        txn: u64,
+        #[serde(with = "wire")]
+        key: Vec<u8>,
+    },
+    Commit {
+        txn: u64,
+    },
+    Rollback {
+        txn: u64,
+    },
+    /// `request`, for the tree `name` rather than the default keyspace.
+    Tree {
+        name: String,
+        request: Box<Request>,
+    },
+    /// Streams a `Response::Event` for every change to a key starting with
+    /// `prefix`, after a first `Response::Ok`, until the client disconnects.
+    /// The connection takes no other requests after this.
+    Watch {
+        #[serde(with = "wire")]
+        prefix: Vec<u8>,
+    },
 }
 
 #[derive(Serialize, Deserialize)]
 pub enum Response {
-    Ok(Option<String>),
+    Ok(#[serde(with = "wire::option")] Option<Vec<u8>>),
     Err(String),
+    /// Key/value pairs in key order, the answer to a scan.
+    Pairs(#[serde(with = "wire::pairs")] Vec<(Vec<u8>, Vec<u8>)>),
+    /// A compare-and-swap found this value instead of the expected one.
+    Conflict(#[serde(with = "wire::option")] Option<Vec<u8>>),
+    /// Milliseconds until the key expires, `None` if it never does.
+    Ttl(Option<u64>),
+    /// The id of the transaction started by `Request::Begin`.
+    Transaction(u64),
+    /// The value of a counter after `Request::Incr`.
+    Counter(i64),
+    /// A change to a watched key, see `Request::Watch`.
+    Event(Event),
+}
+
+/// Result of `KvsEngine::compare_and_swap`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CasOutcome {
+    /// The key held the expected value and now holds the new one.
+    Swapped,
+    /// The key held this value instead; nothing was written.
+    Conflict(Option<Vec<u8>>),
+}
+
+/// What a write did to a key, see `Event`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Op {
+    Set,
+    Remove,
+}
+
+/// A change to a watched key, see `KvsEngine::watch`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Event {
+    #[serde(with = "wire")]
+    pub key: Vec<u8>,
+    pub op: Op,
+    /// The value after the change, `None` for a remove.
+    #[serde(with = "wire::option")]
+    pub value: Option<Vec<u8>>,
 }
 
+/// Key/value pairs in key order, as returned by `KvsEngine::scan`.
+pub type ScanIter = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>>;
+
+/// Whether `range` cannot hold any key. `BTreeMap::range` panics on such a
+/// range instead of returning nothing.
+pub(crate) fn is_empty_range(range: &(Bound<Vec<u8>>, Bound<Vec<u8>>)) -> bool {
+    match range {
+        (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Included(start), Bound::Excluded(end)) => start > end,
+        _ => false,
+    }
+}
+
+/// Fails unless `name` can name a tree: ASCII letters, digits, `_`, `-`
+/// and `.`, not starting with a `.`.
+pub(crate) fn check_tree_name(name: &str) -> Result<()> {
+    let valid = name
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b"_-.".contains(&b));
+    if name.is_empty() || name.starts_with('.') || !valid {
+        return Err(failure::format_err!("invalid tree name: {:?}", name));
+    }
+    Ok(())
+}
+
+/// A key/value store. Keys and values are arbitrary bytes; the methods take
+/// anything that converts to bytes, so `String` and `&str` work as well.
+/// `get` reads a value back as UTF-8 and `get_bytes` as raw bytes.
 pub trait KvsEngine: Clone + Send + 'static {
-    fn set(&self, key: String, value: String) -> Result<()>;
-    fn get(&self, key: String) -> Result<Option<String>>;
-    fn remove(&self, key: String) -> Result<()>;
+    fn set(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()>;
+    /// Reads the value of `key`, failing if it is not valid UTF-8.
+    fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<String>> {
+        into_string(self.get_bytes(key)?)
+    }
+    fn get_bytes(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>>;
+    fn remove(&self, key: impl AsRef<[u8]>) -> Result<()>;
+    /// Sets `key` to `value` and lets it expire after `ttl`. An expired key
+    /// behaves as if it had been removed.
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()>;
+    /// Returns how long `key` has left before it expires, or `None` if it
+    /// does not expire.
+    fn ttl(&self, key: impl AsRef<[u8]>) -> Result<Option<Duration>>;
+    /// Removes the expiry time of `key`, so that it is kept until removed.
+    fn persist(&self, key: impl AsRef<[u8]>) -> Result<()>;
+    /// Applies every operation in `batch` atomically, in order. Removing a
+    /// key that does not exist or has expired is left out rather than
+    /// failing the batch, see `WriteBatch::remove`.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+    /// Sets `key` to `new`, or removes it if `new` is `None`, but only if
+    /// its value is currently `expected`, where `None` means the key does not
+    /// exist. The check and the write happen atomically. An expiry time is
+    /// kept.
+    fn compare_and_swap(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CasOutcome>;
+    /// Adds `delta` to the decimal integer stored at `key`, where a missing
+    /// key counts as 0, and returns the result. Fails if the value is not
+    /// an integer or the result overflows an `i64`. An expiry time is kept.
+    fn incr(&self, key: impl Into<Vec<u8>>, delta: i64) -> Result<i64>;
+    /// Merges `operand` into the value of `key` with the operator
+    /// registered as `operator`, without reading the value first. The
+    /// operator runs when the value is needed, on a later read at the
+    /// latest. An expiry time is kept.
+    fn merge(
+        &self,
+        key: impl Into<Vec<u8>>,
+        operator: &str,
+        operand: impl Into<Vec<u8>>,
+    ) -> Result<()>;
+    /// Registers `operator` for `merge` under `name`, replacing any operator
+    /// of that name. Operators are not stored: they have to be registered
+    /// again after reopening, before values merged with them are read.
+    fn register_merge_operator(
+        &self,
+        name: impl Into<String>,
+        operator: impl Fn(&[u8], Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync + 'static,
+    );
+    /// Returns the pairs whose keys fall in `range`, at most `limit` of them.
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>, limit: Option<usize>) -> Result<ScanIter>;
+    /// Returns the pairs whose keys start with `prefix`.
+    fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Result<ScanIter>;
+
+    type Snapshot: KvsSnapshot;
+    /// Returns a read-only view of the store as it is now. Writes made after
+    /// this call are not visible through it.
+    fn snapshot(&self) -> Result<Self::Snapshot>;
+
+    type Transaction: KvsTransaction;
+    /// Starts a transaction. Its writes are buffered until `commit`, which
+    /// fails with `KvsError::Conflict` if a key it read or wrote has been
+    /// written by someone else since this call.
+    fn begin(&self) -> Result<Self::Transaction>;
+
+    type Watcher: KvsWatcher;
+    /// Returns the changes to keys starting with `prefix` from now on, in
+    /// the order they are made. The value of a merged key is the merged
+    /// one, and an expired key is reported removed once the store drops it.
+    fn watch(&self, prefix: impl Into<Vec<u8>>) -> Result<Self::Watcher>;
+
+    /// Opens the tree `name`, a keyspace of its own within the store, and
+    /// creates it if it does not exist. The handle works like the store
+    /// itself, with its own merge operators, but a write never spans two
+    /// trees. Opening a tree again, from any handle of the store, returns
+    /// the same tree.
+    fn open_tree(&self, name: &str) -> Result<Self>;
+
+    /// Like `open_tree`, but returns `None` rather than creating the tree
+    /// if it does not exist.
+    fn open_existing_tree(&self, name: &str) -> Result<Option<Self>>;
+
+    /// Appends `suffix` to the value of `key`, which is created if it does
+    /// not exist. A merge with the built-in `append` operator.
+    fn append(&self, key: impl Into<Vec<u8>>, suffix: impl Into<Vec<u8>>) -> Result<()> {
+        self.merge(key, merge::APPEND, suffix)
+    }
+}
+
+/// A read-only, point-in-time view of a `KvsEngine`.
+pub trait KvsSnapshot: Send + 'static {
+    /// Reads the value of `key`, failing if it is not valid UTF-8.
+    fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<String>> {
+        into_string(self.get_bytes(key)?)
+    }
+    fn get_bytes(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>>;
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>, limit: Option<usize>) -> Result<ScanIter>;
+    fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Result<ScanIter>;
+}
+
+/// A read-modify-write transaction over several keys, returned by
+/// `KvsEngine::begin`. Dropping it without committing rolls it back.
+pub trait KvsTransaction: Send + 'static {
+    /// Reads `key`, seeing the transaction's own writes. Fails with
+    /// `KvsError::Conflict` once the key has been written since the
+    /// transaction began, as committing could not succeed any more.
+    fn get(&mut self, key: impl AsRef<[u8]>) -> Result<Option<String>> {
+        into_string(self.get_bytes(key)?)
+    }
+    /// Like `get`, but returns the raw bytes.
+    fn get_bytes(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>>;
+    fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()>;
+    /// Removes `key`, failing if it does not exist.
+    fn remove(&mut self, key: impl AsRef<[u8]>) -> Result<()>;
+    /// Applies the buffered writes atomically.
+    fn commit(self) -> Result<()>;
+    /// Discards the buffered writes.
+    fn rollback(self);
+}
+
+/// The changes to a prefix of a `KvsEngine`, returned by `KvsEngine::watch`.
+/// Iterating waits for the next event and stops when the store is closed.
+/// Events queue up until they are taken, so a watcher that is no longer
+/// read from should be dropped.
+pub trait KvsWatcher: Iterator<Item = Event> + Send + 'static {
+    /// Waits at most `timeout` for the next event.
+    fn next_timeout(&mut self, timeout: Duration) -> std::result::Result<Event, RecvTimeoutError>;
+}
+
+fn into_string(value: Option<Vec<u8>>) -> Result<Option<String>> {
+    Ok(value.map(String::from_utf8).transpose()?)
 }
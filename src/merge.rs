@@ -0,0 +1,101 @@
+use crate::Result;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// Combines the current value of a key, `None` if it does not exist, with
+/// a merge operand into the new value. Called with the key, the current
+/// value and the operand, in that order.
+pub type MergeFn = dyn Fn(&[u8], Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync;
+
+/// Name of the built-in operator behind `KvsEngine::append`.
+pub(crate) const APPEND: &str = "append";
+
+/// The merge operators an engine knows, by name. Clones share the same
+/// operators.
+#[derive(Clone)]
+pub(crate) struct MergeOperators {
+    operators: Arc<RwLock<HashMap<String, Arc<MergeFn>>>>,
+}
+
+impl MergeOperators {
+    /// Returns a registry holding only the built-in operators.
+    pub(crate) fn new() -> MergeOperators {
+        let operators = MergeOperators {
+            operators: Arc::default(),
+        };
+        operators.register(APPEND.to_owned(), Arc::new(append));
+        operators
+    }
+
+    /// Adds `operator` under `name`, replacing an operator of the same name.
+    pub(crate) fn register(&self, name: String, operator: Arc<MergeFn>) {
+        self.operators.write().unwrap().insert(name, operator);
+    }
+
+    /// Fails unless an operator is registered under `name`.
+    pub(crate) fn check(&self, name: &str) -> Result<()> {
+        self.get(name).map(|_| ())
+    }
+
+    /// Applies the operator `name` to `existing` and `operand`.
+    pub(crate) fn apply(
+        &self,
+        name: &str,
+        key: &[u8],
+        existing: Option<&[u8]>,
+        operand: &[u8],
+    ) -> Result<Vec<u8>> {
+        Ok(self.get(name)?(key, existing, operand))
+    }
+
+    fn get(&self, name: &str) -> Result<Arc<MergeFn>> {
+        self.operators
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| failure::format_err!("unknown merge operator {:?}", name))
+    }
+}
+
+fn append(_key: &[u8], existing: Option<&[u8]>, suffix: &[u8]) -> Vec<u8> {
+    let mut value = existing.map(<[u8]>::to_vec).unwrap_or_default();
+    value.extend_from_slice(suffix);
+    value
+}
+
+/// Packs an operator name and its operand into one byte string:
+///
+/// ```text
+/// | name_len: u16 | name | operand |
+/// ```
+pub(crate) fn encode_operand(name: &str, operand: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(2 + name.len() + operand.len());
+    buf.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    buf.extend_from_slice(name.as_bytes());
+    buf.extend_from_slice(operand);
+    buf
+}
+
+/// Splits a byte string made by `encode_operand`.
+pub(crate) fn decode_operand(buf: &[u8]) -> Result<(&str, &[u8])> {
+    let invalid = || failure::err_msg("invalid merge operand");
+    let len = u16::from_le_bytes(buf.get(..2).ok_or_else(invalid)?.try_into()?) as usize;
+    let name = buf.get(2..2 + len).ok_or_else(invalid)?;
+    Ok((std::str::from_utf8(name)?, &buf[2 + len..]))
+}
+
+/// Adds `delta` to a counter stored as a decimal integer, where a missing
+/// value counts as 0.
+pub(crate) fn add(current: Option<&[u8]>, delta: i64) -> Result<i64> {
+    let current = match current {
+        None => 0,
+        Some(value) => std::str::from_utf8(value)
+            .ok()
+            .and_then(|value| value.parse::<i64>().ok())
+            .ok_or_else(|| failure::err_msg("value is not an integer"))?,
+    };
+    current
+        .checked_add(delta)
+        .ok_or_else(|| failure::err_msg("increment would overflow"))
+}
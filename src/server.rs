@@ -1,18 +1,27 @@
 use std::{
+    collections::HashMap,
     io::BufReader,
-    net::{SocketAddr, TcpListener, TcpStream},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    ops::Bound,
     sync::{
-        atomic::{AtomicBool, Ordering},
         Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
     },
     thread,
     time::Duration,
 };
 
 use log::{error, info};
+use serde::Deserialize;
 
 use crate::thread_pool::ThreadPool;
-use crate::{KvsEngine, Request, Response};
+use crate::{CasOutcome, KvsEngine, KvsTransaction, KvsWatcher, Request, Response, Result};
+
+// how often a watch checks whether its client is still there
+const WATCH_POLL: Duration = Duration::from_millis(100);
+// connections served at once; those beyond are turned away
+const MAX_CONNECTIONS: usize = 512;
 
 pub struct KvServer<E, P>
 where
@@ -20,35 +29,46 @@ where
     P: ThreadPool,
 {
     engine: Arc<E>,
-    pool: P,
+    pool: Arc<P>,
     listener: TcpListener,
     shutdown: Arc<AtomicBool>,
+    connections: Arc<AtomicUsize>,
 }
 
 impl<E, P> KvServer<E, P>
 where
     E: KvsEngine + Sync + 'static,
-    P: ThreadPool,
+    P: ThreadPool + Send + Sync + 'static,
 {
     pub fn new(addr: SocketAddr, engine: E, pool: P) -> std::io::Result<Self> {
         let listener = TcpListener::bind(addr)?;
         Ok(KvServer {
             engine: Arc::new(engine),
-            pool,
+            pool: Arc::new(pool),
             listener,
             shutdown: Arc::new(AtomicBool::new(false)),
+            connections: Arc::new(AtomicUsize::new(0)),
         })
     }
 
+    /// Accepts connections until shut down. Each connection gets a thread
+    /// that waits for its requests and runs them on the pool, so clients
+    /// that keep their connection open, e.g. to run a transaction, don't
+    /// hold a pool thread between requests. Once `MAX_CONNECTIONS` are
+    /// open, new ones are answered with an error and closed.
     pub fn run(&self) {
         self.listener.set_nonblocking(true).ok();
         loop {
             match self.listener.accept() {
                 Ok((stream, _)) => {
+                    let Some(slot) = Slot::take(&self.connections) else {
+                        let response = Response::Err("too many connections".to_owned());
+                        let _ = serde_json::to_writer(&stream, &response);
+                        continue;
+                    };
                     let engine = Arc::clone(&self.engine);
-                    self.pool.spawn(move || {
-                        handle_client(stream, &*engine);
-                    });
+                    let pool = Arc::clone(&self.pool);
+                    thread::spawn(move || handle_client(stream, slot, &*engine, &*pool));
                 }
                 Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                     if self.shutdown.load(Ordering::SeqCst) {
@@ -76,19 +96,233 @@ where
     }
 }
 
-fn handle_client<E: KvsEngine>(stream: TcpStream, engine: &E) {
-    let reader = BufReader::new(&stream);
+/// One of the `MAX_CONNECTIONS` a server serves at once, given back when
+/// dropped.
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    fn take(connections: &Arc<AtomicUsize>) -> Option<Slot> {
+        connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+                (open < MAX_CONNECTIONS).then_some(open + 1)
+            })
+            .ok()?;
+        Some(Slot(Arc::clone(connections)))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Serves the requests sent over `stream` on `pool` until the client
+/// disconnects. Transactions begun on the connection are rolled back when
+/// it closes.
+fn handle_client<E: KvsEngine>(stream: TcpStream, slot: Slot, engine: &E, pool: &impl ThreadPool) {
+    // The accepted stream may inherit the listener's nonblocking mode
+    if stream.set_nonblocking(false).is_err() {
+        return;
+    }
+    let mut reader = BufReader::new(&stream);
+    let mut transactions = Transactions::default();
     let peer = stream.peer_addr().ok();
-    let request: Request = match serde_json::from_reader(reader) {
-        Ok(req) => req,
+    loop {
+        let mut requests = serde_json::Deserializer::from_reader(&mut reader);
+        let request = match Request::deserialize(&mut requests) {
+            Ok(req) => req,
+            Err(_) => return,
+        };
+        let response = match route(engine, Vec::new(), request) {
+            Err(e) => Response::Err(e.to_string()),
+            Ok((engine, _, Request::Watch { prefix })) => match engine.watch(prefix) {
+                Ok(watcher) => {
+                    if let Some(peer) = peer {
+                        info!("watching for {}", peer);
+                    }
+                    // A watch lasts as long as the client stays, so it gets
+                    // a thread of its own rather than a pool thread
+                    let stream = match stream.try_clone() {
+                        Ok(stream) => stream,
+                        Err(_) => return,
+                    };
+                    thread::spawn(move || {
+                        stream_events(stream, watcher);
+                        drop(slot);
+                    });
+                    return;
+                }
+                Err(e) => Response::Err(e.to_string()),
+            },
+            Ok((engine, tree, request)) => {
+                let (done_tx, done_rx) = mpsc::channel();
+                let mut open = std::mem::take(&mut transactions);
+                pool.spawn(move || {
+                    let response = handle_request(request, &engine, &mut open.of(tree));
+                    let _ = done_tx.send((response, open));
+                });
+                // Nothing comes back if the request panicked
+                match done_rx.recv() {
+                    Ok((response, open)) => {
+                        transactions = open;
+                        response
+                    }
+                    Err(_) => return,
+                }
+            }
+        };
+        if let Some(peer) = peer {
+            info!("handled request from {}", peer);
+        }
+        if serde_json::to_writer(&stream, &response).is_err() {
+            error!("Connection failed");
+            return;
+        }
+    }
+}
+
+/// The engine `request` is for, the names of the trees leading to it
+/// appended to `path`, and the request itself: one wrapped in `Request::Tree` goes to
+/// that tree. Only a write creates the tree, the others fail if it does not
+/// exist.
+fn route<E: KvsEngine>(
+    engine: &E,
+    mut path: Vec<String>,
+    request: Request,
+) -> Result<(E, Vec<String>, Request)> {
+    match request {
+        Request::Tree { name, request } => {
+            let tree = if creates_tree(&request) {
+                engine.open_tree(&name)?
+            } else {
+                engine
+                    .open_existing_tree(&name)?
+                    .ok_or_else(|| failure::format_err!("no such namespace: {}", name))?
+            };
+            path.push(name);
+            route(&tree, path, *request)
+        }
+        request => Ok((engine.clone(), path, request)),
+    }
+}
+
+/// Whether `request` may write to the tree it is sent to, which is then
+/// created if it does not exist.
+fn creates_tree(request: &Request) -> bool {
+    match request {
+        Request::Tree { request, .. } => creates_tree(request),
+        Request::Set { .. }
+        | Request::SetWithTtl { .. }
+        | Request::Batch(_)
+        | Request::CompareAndSwap { .. }
+        | Request::Incr { .. }
+        | Request::Append { .. }
+        | Request::Merge { .. }
+        | Request::Begin => true,
+        _ => false,
+    }
+}
+
+/// Answers a watch with `Response::Ok`, then sends every event of
+/// `watcher` over `stream` until the client disconnects or the store is
+/// closed.
+fn stream_events(stream: TcpStream, mut watcher: impl KvsWatcher) {
+    let send = |response: &Response| serde_json::to_writer(&stream, response).is_ok();
+    if !send(&Response::Ok(None)) {
+        return;
+    }
+    // The client sends nothing more, so a read only returns once it is gone
+    let closed = Arc::new(AtomicBool::new(false));
+    match stream.try_clone() {
+        Ok(mut reader) => {
+            let closed = Arc::clone(&closed);
+            thread::spawn(move || {
+                let _ = std::io::copy(&mut reader, &mut std::io::sink());
+                closed.store(true, Ordering::SeqCst);
+            });
+        }
         Err(_) => return,
-    };
-    let response = match request {
+    }
+    while !closed.load(Ordering::SeqCst) {
+        match watcher.next_timeout(WATCH_POLL) {
+            Ok(event) => {
+                if !send(&Response::Event(event)) {
+                    break;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    // Also ends the read above if the client is still there
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+/// The open transactions of one connection, by the tree they were begun
+/// on and their id. A transaction is only found on its own tree, so its id
+/// can't be used to reach another one.
+struct Transactions<T> {
+    open: HashMap<(Vec<String>, u64), T>,
+    next_id: u64,
+}
+
+impl<T> Default for Transactions<T> {
+    fn default() -> Self {
+        Transactions {
+            open: HashMap::new(),
+            next_id: 1,
+        }
+    }
+}
+
+impl<T> Transactions<T> {
+    /// The transactions of `tree`.
+    fn of(&mut self, tree: Vec<String>) -> TreeTransactions<'_, T> {
+        TreeTransactions { all: self, tree }
+    }
+}
+
+/// The open transactions of one connection on one tree.
+struct TreeTransactions<'a, T> {
+    all: &'a mut Transactions<T>,
+    tree: Vec<String>,
+}
+
+impl<T> TreeTransactions<'_, T> {
+    fn insert(&mut self, txn: T) -> u64 {
+        let id = self.all.next_id;
+        self.all.next_id += 1;
+        self.all.open.insert((self.tree.clone(), id), txn);
+        id
+    }
+
+    fn get(&mut self, id: u64) -> Result<&mut T> {
+        self.all
+            .open
+            .get_mut(&(self.tree.clone(), id))
+            .ok_or_else(|| failure::format_err!("unknown transaction {}", id))
+    }
+
+    fn remove(&mut self, id: u64) -> Result<T> {
+        self.all
+            .open
+            .remove(&(self.tree.clone(), id))
+            .ok_or_else(|| failure::format_err!("unknown transaction {}", id))
+    }
+}
+
+fn handle_request<E: KvsEngine>(
+    request: Request,
+    engine: &E,
+    transactions: &mut TreeTransactions<'_, E::Transaction>,
+) -> Response {
+    match request {
         Request::Set { key, value } => match engine.set(key, value) {
             Ok(()) => Response::Ok(None),
             Err(e) => Response::Err(e.to_string()),
         },
-        Request::Get { key } => match engine.get(key) {
+        Request::Get { key } => match engine.get_bytes(key) {
             Ok(s) => Response::Ok(s),
             Err(e) => Response::Err(e.to_string()),
         },
@@ -96,11 +330,100 @@ fn handle_client<E: KvsEngine>(stream: TcpStream, engine: &E) {
             Ok(_) => Response::Ok(None),
             Err(e) => Response::Err(e.to_string()),
         },
-    };
-    if let Some(peer) = peer {
-        info!("handled request from {}", peer);
-    }
-    if serde_json::to_writer(&stream, &response).is_err() {
-        error!("Connection failed");
+        Request::SetWithTtl { key, value, ttl_ms } => {
+            match engine.set_with_ttl(key, value, Duration::from_millis(ttl_ms)) {
+                Ok(()) => Response::Ok(None),
+                Err(e) => Response::Err(e.to_string()),
+            }
+        }
+        Request::Ttl { key } => match engine.ttl(key) {
+            Ok(ttl) => Response::Ttl(ttl.map(|ttl| ttl.as_millis() as u64)),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Persist { key } => match engine.persist(key) {
+            Ok(()) => Response::Ok(None),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Batch(batch) => match engine.write_batch(batch) {
+            Ok(()) => Response::Ok(None),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::CompareAndSwap { key, expected, new } => {
+            match engine.compare_and_swap(key, expected, new) {
+                Ok(CasOutcome::Swapped) => Response::Ok(None),
+                Ok(CasOutcome::Conflict(current)) => Response::Conflict(current),
+                Err(e) => Response::Err(e.to_string()),
+            }
+        }
+        Request::Incr { key, delta } => match engine.incr(key, delta) {
+            Ok(value) => Response::Counter(value),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Append { key, suffix } => match engine.append(key, suffix) {
+            Ok(()) => Response::Ok(None),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Merge {
+            key,
+            operator,
+            operand,
+        } => match engine.merge(key, &operator, operand) {
+            Ok(()) => Response::Ok(None),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Scan { start, end, limit } => {
+            let start = start.map_or(Bound::Unbounded, Bound::Included);
+            let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+            match engine
+                .scan((start, end), limit)
+                .and_then(|pairs| pairs.collect())
+            {
+                Ok(pairs) => Response::Pairs(pairs),
+                Err(e) => Response::Err(e.to_string()),
+            }
+        }
+        Request::ScanPrefix { prefix, limit } => match engine
+            .scan_prefix(&prefix)
+            .and_then(|pairs| pairs.take(limit.unwrap_or(usize::MAX)).collect())
+        {
+            Ok(pairs) => Response::Pairs(pairs),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Begin => match engine.begin() {
+            Ok(txn) => Response::Transaction(transactions.insert(txn)),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::TxnGet { txn, key } => {
+            match transactions.get(txn).and_then(|txn| txn.get_bytes(key)) {
+                Ok(value) => Response::Ok(value),
+                Err(e) => Response::Err(e.to_string()),
+            }
+        }
+        Request::TxnSet { txn, key, value } => {
+            match transactions.get(txn).and_then(|txn| txn.set(key, value)) {
+                Ok(()) => Response::Ok(None),
+                Err(e) => Response::Err(e.to_string()),
+            }
+        }
+        Request::TxnRemove { txn, key } => {
+            match transactions.get(txn).and_then(|txn| txn.remove(key)) {
+                Ok(()) => Response::Ok(None),
+                Err(e) => Response::Err(e.to_string()),
+            }
+        }
+        Request::Commit { txn } => match transactions.remove(txn).and_then(|txn| txn.commit()) {
+            Ok(()) => Response::Ok(None),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Rollback { txn } => match transactions.remove(txn) {
+            Ok(txn) => {
+                txn.rollback();
+                Response::Ok(None)
+            }
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Tree { .. } | Request::Watch { .. } => {
+            Response::Err("unsupported request".to_owned())
+        }
     }
 }
@@ -1,40 +1,688 @@
-use crate::{KvsEngine, Result};
+use crate::background::Periodic;
+use crate::expiry::{self, REAP_INTERVAL, now_millis};
+use crate::merge::{self, MergeFn, MergeOperators};
+use crate::sync::GroupCommit;
+use crate::txn::{TxnState, Versions};
+use crate::{
+    CasOutcome, Cmd, Event, KvsEngine, KvsSnapshot, KvsTransaction, KvsWatcher, Op, Result,
+    ScanIter, SyncPolicy, WriteBatch, check_tree_name, is_empty_range,
+};
+use failure::Error;
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError, Transactional,
+    TransactionalTree, UnabortableTransactionError,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::ops::{Bound, RangeBounds};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+// How often `snapshot` copies the tree while writes go on before it holds
+// them off
+const SNAPSHOT_ATTEMPTS: usize = 3;
 
 #[derive(Clone)]
 pub struct SledKvsEngine {
     db: sled::Db,
+    // the keys and values: the default tree of `db`, or the one of a tree
+    // opened with `open_tree`
+    data: sled::Tree,
+    // expiry time of every key in `data` that has one, as a big-endian u64
+    // of milliseconds since the Unix epoch
+    ttl: sled::Tree,
+    sync_policy: SyncPolicy,
+    // number of writes so far, the tickets for group commit
+    writes: Arc<AtomicU64>,
+    group_commit: Arc<GroupCommit>,
+    // Writes hold this shared while they modify the tree and record it in
+    // `versions`; a snapshot holds it exclusively to tell whether writes
+    // landed while it copied the tree, and so does a transaction while it
+    // begins or commits. Shared by all trees.
+    snapshot_lock: Arc<RwLock<()>>,
+    // keys written while transactions are open
+    versions: Arc<Mutex<Versions>>,
+    // the operators behind sled's merge operator, which can only be set once
+    operators: MergeOperators,
+    // only held so that the reaper thread stops with the last clone
+    _reaper: Arc<Periodic>,
+    // the trees opened so far, so that all handles of one share its state
+    trees: Arc<Mutex<HashMap<String, Keyspace>>>,
+}
+
+/// The state a `SledKvsEngine` keeps for each of its trees.
+#[derive(Clone)]
+struct Keyspace {
+    data: sled::Tree,
+    ttl: sled::Tree,
+    versions: Arc<Mutex<Versions>>,
+    operators: MergeOperators,
+    reaper: Arc<Periodic>,
+}
+
+impl Keyspace {
+    /// Sets up the tree `data` with its expiry tree `ttl`: merges go through
+    /// a new set of operators, and a reaper thread drops expired keys.
+    fn new(data: sled::Tree, ttl: sled::Tree, snapshot_lock: &Arc<RwLock<()>>) -> Keyspace {
+        let operators = MergeOperators::new();
+        let dispatch = operators.clone();
+        data.set_merge_operator(move |key: &[u8], old: Option<&[u8]>, operand: &[u8]| {
+            let merged = merge::decode_operand(operand)
+                .and_then(|(name, operand)| dispatch.apply(name, key, old, operand));
+            match merged {
+                Ok(value) => Some(value),
+                Err(e) => {
+                    // `merge` checks the operator first, so this is not expected
+                    log::error!("merge failed, keeping the old value: {}", e);
+                    old.map(<[u8]>::to_vec)
+                }
+            }
+        });
+        let versions = Arc::new(Mutex::new(Versions::default()));
+        let reaper = {
+            let (data, ttl, snapshot_lock) = (data.clone(), ttl.clone(), Arc::clone(snapshot_lock));
+            let versions = Arc::clone(&versions);
+            Periodic::spawn(REAP_INTERVAL, move || {
+                let _write = snapshot_lock.read().unwrap();
+                match reap(&data, &ttl) {
+                    // tells a snapshot being copied that the tree changed
+                    Ok(true) => versions.lock().unwrap().tick(),
+                    Ok(false) => {}
+                    Err(e) => log::error!("dropping expired keys failed: {}", e),
+                }
+            })
+        };
+        Keyspace {
+            data,
+            ttl,
+            versions,
+            operators,
+            reaper: Arc::new(reaper),
+        }
+    }
 }
 
 impl SledKvsEngine {
+    /// Opens the database with `SyncPolicy::Always`.
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
-        let db = sled::open(path.into())?;
-        Ok(SledKvsEngine { db })
+        SledKvsEngine::open_with_sync(path, SyncPolicy::Always)
+    }
+
+    /// Opens the database with the given sync policy. `Interval` is handed
+    /// to sled's own background flusher; `Never` leaves it at sled's default.
+    pub fn open_with_sync(path: impl Into<PathBuf>, sync_policy: SyncPolicy) -> Result<Self> {
+        let mut config = sled::Config::new().path(path.into());
+        if let SyncPolicy::Interval(interval) = sync_policy {
+            config = config.flush_every_ms(Some(interval.as_millis().max(1) as u64));
+        }
+        let db = config.open()?;
+        let snapshot_lock = Arc::new(RwLock::new(()));
+        let keyspace = Keyspace::new((*db).clone(), db.open_tree("ttl")?, &snapshot_lock);
+        Ok(SledKvsEngine {
+            db,
+            data: keyspace.data,
+            ttl: keyspace.ttl,
+            sync_policy,
+            writes: Arc::new(AtomicU64::new(0)),
+            group_commit: Arc::new(GroupCommit::new()),
+            snapshot_lock,
+            versions: keyspace.versions,
+            operators: keyspace.operators,
+            _reaper: keyspace.reaper,
+            trees: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Runs `f` as a transaction over the data and the expiry trees, as a
+    /// write to `keys`.
+    fn transaction<T>(
+        &self,
+        keys: &[&[u8]],
+        f: impl Fn(&TransactionalTree, &TransactionalTree) -> ConflictableTransactionResult<T, Error>,
+    ) -> Result<T> {
+        let _write = self.snapshot_lock.read().unwrap();
+        let value = self.transaction_locked(f)?;
+        self.versions.lock().unwrap().record(keys.iter().copied());
+        Ok(value)
+    }
+
+    /// Like `transaction`, for a caller that holds `snapshot_lock` and
+    /// records the write itself.
+    fn transaction_locked<T>(
+        &self,
+        f: impl Fn(&TransactionalTree, &TransactionalTree) -> ConflictableTransactionResult<T, Error>,
+    ) -> Result<T> {
+        let result = (&self.data, &self.ttl).transaction(|(data, ttl)| f(data, ttl));
+        match result {
+            Ok(value) => Ok(value),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+            Err(TransactionError::Abort(e)) => Err(e),
+        }
+    }
+
+    /// Whether `key` has expired.
+    fn is_expired(&self, key: &[u8]) -> Result<bool> {
+        Ok(is_expired(self.ttl.get(key)?, now_millis()))
+    }
+
+    /// Expiry time of `key`, or an error if it does not exist or has expired.
+    fn live_expiry(&self, key: &[u8]) -> Result<Option<u64>> {
+        if !self.data.contains_key(key)? || self.is_expired(key)? {
+            return Err(failure::err_msg("Key not found"));
+        }
+        Ok(self.ttl.get(key)?.map(|at| decode_expiry(&at)))
+    }
+
+    /// The tick of `versions` once the writes in flight are done with it.
+    fn write_clock(&self) -> u64 {
+        let _quiet = self.snapshot_lock.write().unwrap();
+        self.versions.lock().unwrap().clock()
+    }
+
+    /// Makes the write that was just applied durable, if the sync policy
+    /// asks for it before the write is acknowledged.
+    fn sync(&self) -> Result<()> {
+        match self.sync_policy {
+            SyncPolicy::Always => {
+                self.db.flush()?;
+            }
+            SyncPolicy::GroupCommit => {
+                let ticket = self.writes.fetch_add(1, Ordering::SeqCst) + 1;
+                self.group_commit.wait(
+                    ticket,
+                    || self.writes.load(Ordering::SeqCst),
+                    || {
+                        self.db.flush()?;
+                        Ok(())
+                    },
+                )?;
+            }
+            SyncPolicy::Never | SyncPolicy::Interval(_) => {}
+        }
+        Ok(())
     }
 }
 
 impl KvsEngine for SledKvsEngine {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.db.insert(key.as_bytes(), value.as_bytes())?;
-        self.db.flush()?;
-        Ok(())
+    fn set(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        self.transaction(&[&key], |data, ttl| {
+            data.insert(&key[..], &value[..])?;
+            ttl.remove(&key[..])?;
+            Ok(())
+        })?;
+        self.sync()
     }
-    fn get(&self, key: String) -> Result<Option<String>> {
-        let value = self.db.get(key.as_bytes())?;
+    fn get_bytes(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let key = key.as_ref();
+        let value = self.data.get(key)?;
         match value {
-            Some(bytes) => {
-                let s = String::from_utf8(bytes.to_vec())?;
-                Ok(Some(s))
+            Some(bytes) if !self.is_expired(key)? => Ok(Some(bytes.to_vec())),
+            _ => Ok(None),
+        }
+    }
+    fn remove(&self, key: impl AsRef<[u8]>) -> Result<()> {
+        let key = key.as_ref();
+        let existed = self.transaction(&[key], |data, ttl| {
+            let old = data.remove(key)?;
+            let expires_at = ttl.remove(key)?;
+            Ok(old.is_some() && !is_expired(expires_at, now_millis()))
+        })?;
+        self.sync()?;
+        match existed {
+            true => Ok(()),
+            false => Err(failure::err_msg("Key not found")),
+        }
+    }
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        let expires_at = expiry::expires_at(ttl);
+        self.transaction(&[&key], |data, ttl| {
+            data.insert(&key[..], &value[..])?;
+            ttl.insert(&key[..], &expires_at.to_be_bytes())?;
+            Ok(())
+        })?;
+        self.sync()
+    }
+    fn ttl(&self, key: impl AsRef<[u8]>) -> Result<Option<Duration>> {
+        Ok(self.live_expiry(key.as_ref())?.map(expiry::time_left))
+    }
+    fn persist(&self, key: impl AsRef<[u8]>) -> Result<()> {
+        let key = key.as_ref();
+        if self.live_expiry(key)?.is_none() {
+            return Ok(());
+        }
+        let persisted = self.transaction(&[key], |data, ttl| {
+            let expires_at = ttl.get(key)?;
+            if data.get(key)?.is_none() || is_expired(expires_at, now_millis()) {
+                return Ok(false);
+            }
+            ttl.remove(key)?;
+            Ok(true)
+        })?;
+        if !persisted {
+            return Err(failure::err_msg("Key not found"));
+        }
+        self.sync()
+    }
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        batch.check_operators(&self.operators)?;
+        let cmds = batch.into_cmds();
+        let keys: Vec<&[u8]> = cmds.iter().map(Cmd::key).collect();
+        self.transaction(&keys, |data, ttl| apply(data, ttl, &cmds, &self.operators))?;
+        self.sync()
+    }
+    fn compare_and_swap(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CasOutcome> {
+        let key = key.into();
+        let current = self.transaction(&[&key], |data, ttl| {
+            let expires_at = ttl.get(&key[..])?;
+            let current = data
+                .get(&key[..])?
+                .filter(|_| !is_expired(expires_at, now_millis()));
+            if current.as_deref() != expected.as_deref() {
+                return Ok(Some(current));
+            }
+            match &new {
+                Some(value) => data.insert(&key[..], &value[..])?,
+                None => data.remove(&key[..])?,
+            };
+            // A live key keeps its expiry time
+            if new.is_none() || current.is_none() {
+                ttl.remove(&key[..])?;
+            }
+            Ok(None)
+        })?;
+        match current {
+            None => {
+                self.sync()?;
+                Ok(CasOutcome::Swapped)
+            }
+            Some(current) => Ok(CasOutcome::Conflict(current.map(|value| value.to_vec()))),
+        }
+    }
+    fn incr(&self, key: impl Into<Vec<u8>>, delta: i64) -> Result<i64> {
+        let key = key.into();
+        let value = self.transaction(&[&key], |data, ttl| {
+            let current = live_value(data, ttl, &key)?;
+            let value = merge::add(current.as_deref(), delta)
+                .map_err(ConflictableTransactionError::Abort)?;
+            data.insert(&key[..], value.to_string().as_bytes())?;
+            Ok(value)
+        })?;
+        self.sync()?;
+        Ok(value)
+    }
+    /// Merges through sled's own merge operator. That one cannot see the
+    /// expiry tree, so an expired value is dropped first.
+    fn merge(
+        &self,
+        key: impl Into<Vec<u8>>,
+        operator: &str,
+        operand: impl Into<Vec<u8>>,
+    ) -> Result<()> {
+        self.operators.check(operator)?;
+        let key = key.into();
+        let operand = merge::encode_operand(operator, &operand.into());
+        {
+            let _write = self.snapshot_lock.read().unwrap();
+            if self.is_expired(&key)? {
+                self.transaction_locked(|data, ttl| {
+                    if live_value(data, ttl, &key)?.is_none() {
+                        data.remove(&key[..])?;
+                    }
+                    Ok(())
+                })?;
             }
-            None => Ok(None),
+            self.data.merge(&key, operand)?;
+            self.versions.lock().unwrap().record([&key[..]]);
         }
+        self.sync()
+    }
+    fn register_merge_operator(
+        &self,
+        name: impl Into<String>,
+        operator: impl Fn(&[u8], Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync + 'static,
+    ) {
+        let operator: Arc<MergeFn> = Arc::new(operator);
+        self.operators.register(name.into(), operator);
     }
-    fn remove(&self, key: String) -> Result<()> {
-        let old = self.db.remove(key.as_bytes())?;
-        self.db.flush()?;
-        match old {
-            Some(_) => Ok(()),
-            None => Err(failure::err_msg("Key not found")),
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>, limit: Option<usize>) -> Result<ScanIter> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let iter = live_pairs(self.data.range(range), self.ttl.clone());
+        Ok(Box::new(iter.take(limit.unwrap_or(usize::MAX))))
+    }
+    fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Result<ScanIter> {
+        let iter = live_pairs(self.data.scan_prefix(prefix), self.ttl.clone());
+        Ok(Box::new(iter))
+    }
+
+    type Snapshot = SledSnapshot;
+
+    /// sled cannot pin a version of the tree, so this copies it into memory,
+    /// which takes time and memory in proportion to the size of the tree.
+    /// Writes go on while the copy is made, and it is made again if any of
+    /// them landed in the meantime. Only after a few such attempts are
+    /// writes held off for the copy. Keys that expire later stay in the
+    /// copy, as they do in a `KvStore` snapshot.
+    fn snapshot(&self) -> Result<SledSnapshot> {
+        for _ in 0..SNAPSHOT_ATTEMPTS {
+            let start = self.write_clock();
+            let pairs = live_pairs(self.data.iter(), self.ttl.clone()).collect::<Result<_>>()?;
+            if self.write_clock() == start {
+                return Ok(SledSnapshot { pairs });
+            }
         }
+        let _copying = self.snapshot_lock.write().unwrap();
+        let pairs = live_pairs(self.data.iter(), self.ttl.clone()).collect::<Result<_>>()?;
+        Ok(SledSnapshot { pairs })
+    }
+
+    type Transaction = SledTransaction;
+
+    fn begin(&self) -> Result<SledTransaction> {
+        // No write may be between applying and recording its keys
+        let _begin = self.snapshot_lock.write().unwrap();
+        Ok(SledTransaction {
+            state: TxnState::begin(&self.versions),
+            engine: self.clone(),
+        })
     }
+
+    type Watcher = SledWatcher;
+
+    fn watch(&self, prefix: impl Into<Vec<u8>>) -> Result<SledWatcher> {
+        Ok(SledWatcher(self.data.watch_prefix(prefix.into())))
+    }
+
+    /// A tree is a sled tree of its own, with another one for its expiry
+    /// times.
+    fn open_tree(&self, name: &str) -> Result<SledKvsEngine> {
+        check_tree_name(name)?;
+        let mut trees = self.trees.lock().unwrap();
+        let keyspace = match trees.get(name) {
+            Some(keyspace) => keyspace.clone(),
+            None => {
+                let data = self.db.open_tree(format!("tree:{}", name))?;
+                let ttl = self.db.open_tree(format!("ttl:{}", name))?;
+                let keyspace = Keyspace::new(data, ttl, &self.snapshot_lock);
+                trees.insert(name.to_owned(), keyspace.clone());
+                keyspace
+            }
+        };
+        Ok(SledKvsEngine {
+            data: keyspace.data,
+            ttl: keyspace.ttl,
+            versions: keyspace.versions,
+            operators: keyspace.operators,
+            _reaper: keyspace.reaper,
+            ..self.clone()
+        })
+    }
+
+    fn open_existing_tree(&self, name: &str) -> Result<Option<SledKvsEngine>> {
+        check_tree_name(name)?;
+        let open = self.trees.lock().unwrap().contains_key(name);
+        let data = format!("tree:{}", name);
+        if open
+            || self
+                .db
+                .tree_names()
+                .iter()
+                .any(|tree| tree == data.as_bytes())
+        {
+            self.open_tree(name).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// A transaction on a `SledKvsEngine`, returned by `KvsEngine::begin`.
+///
+/// sled's own transactions run in a closure, so the writes are buffered
+/// here and handed to one sled transaction on commit.
+pub struct SledTransaction {
+    engine: SledKvsEngine,
+    state: TxnState,
+}
+
+impl KvsTransaction for SledTransaction {
+    fn get_bytes(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let key = key.as_ref();
+        if let Some(value) = self.state.cached(key) {
+            return Ok(value);
+        }
+        let value = self.engine.get_bytes(key)?;
+        self.state.observe(key, value)
+    }
+
+    fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        self.state.write(key.into(), Some(value.into()));
+        Ok(())
+    }
+
+    fn remove(&mut self, key: impl AsRef<[u8]>) -> Result<()> {
+        let key = key.as_ref();
+        if self.get_bytes(key)?.is_none() {
+            return Err(failure::err_msg("Key not found"));
+        }
+        self.state.write(key.to_vec(), None);
+        Ok(())
+    }
+
+    fn commit(mut self) -> Result<()> {
+        let engine = &self.engine;
+        {
+            let _commit = engine.snapshot_lock.write().unwrap();
+            self.state.check(&engine.versions.lock().unwrap())?;
+            let cmds = self.state.finish();
+            if cmds.is_empty() {
+                return Ok(());
+            }
+            engine.transaction_locked(|data, ttl| apply(data, ttl, &cmds, &engine.operators))?;
+            engine
+                .versions
+                .lock()
+                .unwrap()
+                .record(cmds.iter().map(Cmd::key));
+        }
+        engine.sync()
+    }
+
+    fn rollback(self) {}
+}
+
+/// Applies `cmds` inside a sled transaction over the data and expiry trees.
+/// Merges are resolved right away, with `operators`.
+fn apply(
+    data: &TransactionalTree,
+    ttl: &TransactionalTree,
+    cmds: &[Cmd],
+    operators: &MergeOperators,
+) -> ConflictableTransactionResult<(), Error> {
+    for cmd in cmds {
+        match cmd {
+            Cmd::Set {
+                key,
+                value,
+                expires_at,
+            } => {
+                data.insert(&key[..], &value[..])?;
+                match expires_at {
+                    Some(at) => ttl.insert(&key[..], &at.to_be_bytes())?,
+                    None => ttl.remove(&key[..])?,
+                };
+            }
+            Cmd::Rm { key } => {
+                data.remove(&key[..])?;
+                ttl.remove(&key[..])?;
+            }
+            Cmd::Merge {
+                key,
+                operator,
+                operand,
+            } => {
+                let current = live_value(data, ttl, key)?;
+                let value = operators
+                    .apply(operator, key, current.as_deref(), operand)
+                    .map_err(ConflictableTransactionError::Abort)?;
+                data.insert(&key[..], value)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The value of `key` inside a transaction, `None` if it does not exist or
+/// has expired. The expiry time of an expired key is removed, as the caller
+/// is about to write the key.
+fn live_value(
+    data: &TransactionalTree,
+    ttl: &TransactionalTree,
+    key: &[u8],
+) -> std::result::Result<Option<sled::IVec>, UnabortableTransactionError> {
+    let expires_at = ttl.get(key)?;
+    if is_expired(expires_at, now_millis()) {
+        ttl.remove(key)?;
+        return Ok(None);
+    }
+    data.get(key)
+}
+
+/// The changes to a prefix of a `SledKvsEngine`, returned by
+/// `KvsEngine::watch`.
+pub struct SledWatcher(sled::Subscriber);
+
+impl Iterator for SledWatcher {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        self.0.next().map(into_event)
+    }
+}
+
+impl KvsWatcher for SledWatcher {
+    fn next_timeout(&mut self, timeout: Duration) -> std::result::Result<Event, RecvTimeoutError> {
+        self.0.next_timeout(timeout).map(into_event)
+    }
+}
+
+fn into_event(event: sled::Event) -> Event {
+    match event {
+        sled::Event::Insert { key, value } => Event {
+            key: key.to_vec(),
+            op: Op::Set,
+            value: Some(value.to_vec()),
+        },
+        sled::Event::Remove { key } => Event {
+            key: key.to_vec(),
+            op: Op::Remove,
+            value: None,
+        },
+    }
+}
+
+/// A copy of a `SledKvsEngine`, returned by `KvsEngine::snapshot`.
+pub struct SledSnapshot {
+    pairs: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl KvsSnapshot for SledSnapshot {
+    fn get_bytes(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        Ok(self.pairs.get(key.as_ref()).cloned())
+    }
+
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>, limit: Option<usize>) -> Result<ScanIter> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        if is_empty_range(&range) {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let pairs: Vec<_> = self
+            .pairs
+            .range(range)
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|(key, value)| Ok((key.clone(), value.clone())))
+            .collect();
+        Ok(Box::new(pairs.into_iter()))
+    }
+
+    fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Result<ScanIter> {
+        let prefix = prefix.as_ref();
+        let pairs: Vec<_> = self
+            .pairs
+            .range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| Ok((key.clone(), value.clone())))
+            .collect();
+        Ok(Box::new(pairs.into_iter()))
+    }
+}
+
+/// Removes every expired key from `db`. Returns whether there was any.
+fn reap(data: &sled::Tree, ttl: &sled::Tree) -> Result<bool> {
+    let now = now_millis();
+    let mut reaped = false;
+    for entry in ttl.iter() {
+        let (key, expires_at) = entry?;
+        if !is_expired(Some(expires_at.clone()), now) {
+            continue;
+        }
+        let result = (data, ttl).transaction(|(data, ttl)| {
+            // the key may have been set again since it was read above
+            if ttl.get(&key)?.as_ref() == Some(&expires_at) {
+                data.remove(&key)?;
+                ttl.remove(&key)?;
+            }
+            Ok::<_, sled::transaction::ConflictableTransactionError<Infallible>>(())
+        });
+        if let Err(TransactionError::Storage(e)) = result {
+            return Err(e.into());
+        }
+        reaped = true;
+    }
+    Ok(reaped)
+}
+
+/// Drops the pairs of `iter` whose keys have expired according to `ttl`.
+fn live_pairs(
+    iter: impl Iterator<Item = sled::Result<(sled::IVec, sled::IVec)>>,
+    ttl: sled::Tree,
+) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> {
+    let now = now_millis();
+    iter.filter_map(move |pair| {
+        let live = match &pair {
+            Ok((key, _)) => match ttl.get(key) {
+                Ok(expires_at) => !is_expired(expires_at, now),
+                Err(e) => return Some(Err(e.into())),
+            },
+            Err(_) => true,
+        };
+        live.then(|| decode_pair(pair))
+    })
+}
+
+fn is_expired(expires_at: Option<sled::IVec>, now: u64) -> bool {
+    expires_at.is_some_and(|at| decode_expiry(&at) <= now)
+}
+
+fn decode_expiry(buf: &[u8]) -> u64 {
+    u64::from_be_bytes(buf.try_into().unwrap_or([0; 8]))
+}
+
+fn decode_pair(pair: sled::Result<(sled::IVec, sled::IVec)>) -> Result<(Vec<u8>, Vec<u8>)> {
+    let (key, value) = pair?;
+    Ok((key.to_vec(), value.to_vec()))
 }
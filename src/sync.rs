@@ -0,0 +1,99 @@
+use crate::Result;
+use std::str::FromStr;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// When a write is made durable, relative to acknowledging it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Hand writes to the OS and let it decide when they reach the disk.
+    /// An acknowledged write can be lost on power failure.
+    #[default]
+    Never,
+    /// fsync before every write is acknowledged.
+    Always,
+    /// fsync before every write is acknowledged, but let writers that
+    /// arrive while a sync is running share the next one.
+    GroupCommit,
+    /// fsync in the background at this interval. Writes acknowledged since
+    /// the last sync can be lost.
+    Interval(Duration),
+}
+
+impl FromStr for SyncPolicy {
+    type Err = String;
+
+    /// Parses `never`, `always`, `group-commit` or `interval:<millis>`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(SyncPolicy::Never),
+            "always" => Ok(SyncPolicy::Always),
+            "group-commit" => Ok(SyncPolicy::GroupCommit),
+            _ => s
+                .strip_prefix("interval:")
+                .and_then(|millis| millis.parse().ok())
+                .map(|millis| SyncPolicy::Interval(Duration::from_millis(millis)))
+                .ok_or_else(|| format!("invalid sync policy: {}", s)),
+        }
+    }
+}
+
+/// Coordinates `SyncPolicy::GroupCommit`. Every write gets an increasing
+/// ticket; a writer waiting for its ticket either runs one sync that covers
+/// every write issued so far, or waits for the sync in progress to finish
+/// and checks again.
+pub(crate) struct GroupCommit {
+    state: Mutex<GroupState>,
+    synced: Condvar,
+}
+
+struct GroupState {
+    // every ticket up to this one is durable
+    synced: u64,
+    // whether some writer is running a sync right now
+    syncing: bool,
+}
+
+impl GroupCommit {
+    pub(crate) fn new() -> GroupCommit {
+        GroupCommit {
+            state: Mutex::new(GroupState {
+                synced: 0,
+                syncing: false,
+            }),
+            synced: Condvar::new(),
+        }
+    }
+
+    /// Blocks until `ticket` is durable. `latest` returns the newest ticket
+    /// whose write has been handed to the OS, and `sync` makes all of those
+    /// durable.
+    pub(crate) fn wait(
+        &self,
+        ticket: u64,
+        latest: impl Fn() -> u64,
+        sync: impl Fn() -> Result<()>,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced >= ticket {
+                return Ok(());
+            }
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+            state.syncing = true;
+            let target = latest();
+            drop(state);
+            let result = sync();
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            if result.is_ok() {
+                state.synced = state.synced.max(target);
+            }
+            self.synced.notify_all();
+            result?;
+        }
+    }
+}
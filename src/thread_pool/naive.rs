@@ -1,5 +1,5 @@
-use crate::thread_pool::ThreadPool;
 use crate::Result;
+use crate::thread_pool::ThreadPool;
 pub struct NaiveThreadPool;
 
 impl ThreadPool for NaiveThreadPool {
@@ -1,6 +1,6 @@
 use std::{
     panic::{self, AssertUnwindSafe},
-    sync::{mpsc, Arc, Mutex},
+    sync::{Arc, Mutex, mpsc},
     thread::{self, JoinHandle},
 };
 
@@ -28,13 +28,15 @@ impl super::ThreadPool for SharedQueueThreadPool {
         let receiver = Arc::new(Mutex::new(rx));
         for _ in 0..threads {
             let receiver = receiver.clone();
-            let handle = thread::spawn(move || loop {
-                let item = receiver.lock().unwrap().recv();
-                match item {
-                    Ok(Message::Job(j)) => {
-                        let _ = panic::catch_unwind(AssertUnwindSafe(j));
+            let handle = thread::spawn(move || {
+                loop {
+                    let item = receiver.lock().unwrap().recv();
+                    match item {
+                        Ok(Message::Job(j)) => {
+                            let _ = panic::catch_unwind(AssertUnwindSafe(j));
+                        }
+                        Ok(Message::Terminate) | Err(_) => break,
                     }
-                    Ok(Message::Terminate) | Err(_) => break,
                 }
             });
             workers.push(Worker {
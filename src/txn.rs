@@ -0,0 +1,170 @@
+use crate::{Cmd, KvsError, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+/// Tracks which keys were written while transactions are open, so that a
+/// commit can tell whether anything it depends on changed after it began.
+///
+/// Every write ticks a logical clock. A transaction remembers the clock at
+/// `begin`; a key last written at a later tick conflicts with it. Writes are
+/// only remembered while some transaction is open, and only for as long as
+/// one that began before them is.
+#[derive(Default)]
+pub(crate) struct Versions {
+    clock: u64,
+    // begin tick of every open transaction -> how many began at it
+    active: BTreeMap<u64, usize>,
+    // key -> tick of its last write
+    written: HashMap<Vec<u8>, u64>,
+}
+
+impl Versions {
+    /// Registers a new transaction and returns its begin tick.
+    pub(crate) fn begin(&mut self) -> u64 {
+        *self.active.entry(self.clock).or_default() += 1;
+        self.clock
+    }
+
+    /// Unregisters a transaction that began at `begin` and forgets the
+    /// writes no open transaction can conflict with any more.
+    pub(crate) fn end(&mut self, begin: u64) {
+        if let Some(count) = self.active.get_mut(&begin) {
+            *count -= 1;
+            if *count == 0 {
+                self.active.remove(&begin);
+            }
+        }
+        match self.active.keys().next() {
+            Some(&oldest) => self.written.retain(|_, tick| *tick > oldest),
+            None => self.written.clear(),
+        }
+    }
+
+    /// Records a write of `keys`. Must be called while the write is
+    /// serialized with commits, see the engines.
+    pub(crate) fn record<'a>(&mut self, keys: impl IntoIterator<Item = &'a [u8]>) {
+        self.clock += 1;
+        if self.active.is_empty() {
+            return;
+        }
+        for key in keys {
+            self.written.insert(key.to_vec(), self.clock);
+        }
+    }
+
+    /// Counts a write that no transaction can conflict with, such as
+    /// dropping keys that had already expired.
+    pub(crate) fn tick(&mut self) {
+        self.clock += 1;
+    }
+
+    /// The current tick, which moves on with every write.
+    pub(crate) fn clock(&self) -> u64 {
+        self.clock
+    }
+
+    /// Whether `key` was written after `begin`.
+    pub(crate) fn written_since(&self, key: &[u8], begin: u64) -> bool {
+        self.written.get(key).is_some_and(|&tick| tick > begin)
+    }
+}
+
+/// The part of a transaction both engines share: the begin tick, the
+/// values read so far and the writes buffered until commit.
+pub(crate) struct TxnState {
+    versions: Arc<Mutex<Versions>>,
+    begin: u64,
+    // key -> value it had when first read
+    reads: HashMap<Vec<u8>, Option<Vec<u8>>>,
+    // key -> new value, `None` to remove it
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    ended: bool,
+}
+
+impl TxnState {
+    pub(crate) fn begin(versions: &Arc<Mutex<Versions>>) -> TxnState {
+        let begin = versions.lock().unwrap().begin();
+        TxnState {
+            versions: Arc::clone(versions),
+            begin,
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+            ended: false,
+        }
+    }
+
+    /// The value of `key` as this transaction sees it, if it was read or
+    /// written before.
+    pub(crate) fn cached(&self, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        self.writes
+            .get(key)
+            .or_else(|| self.reads.get(key))
+            .cloned()
+    }
+
+    /// Remembers `value` as read from the store. Fails if the key was
+    /// written since the transaction began, in which case `value` may not
+    /// be what the transaction should see and the commit would fail anyway.
+    pub(crate) fn observe(
+        &mut self,
+        key: &[u8],
+        value: Option<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>> {
+        if self.versions.lock().unwrap().written_since(key, self.begin) {
+            return Err(KvsError::Conflict.into());
+        }
+        self.reads.insert(key.to_vec(), value.clone());
+        Ok(value)
+    }
+
+    pub(crate) fn write(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) {
+        self.writes.insert(key, value);
+    }
+
+    /// Fails if any key this transaction read or wrote was written since
+    /// it began. The caller must keep other writes out until it has applied
+    /// the commands returned by `finish`.
+    pub(crate) fn check(&self, versions: &Versions) -> Result<()> {
+        let mut keys = self.reads.keys().chain(self.writes.keys());
+        match keys.any(|key| versions.written_since(key, self.begin)) {
+            true => Err(KvsError::Conflict.into()),
+            false => Ok(()),
+        }
+    }
+
+    pub(crate) fn versions(&self) -> &Arc<Mutex<Versions>> {
+        &self.versions
+    }
+
+    /// Ends the transaction and returns its writes as commands. Removes of
+    /// keys that were already missing are left out.
+    pub(crate) fn finish(&mut self) -> Vec<Cmd> {
+        self.end();
+        let reads = std::mem::take(&mut self.reads);
+        std::mem::take(&mut self.writes)
+            .into_iter()
+            .filter_map(|(key, value)| match value {
+                Some(value) => Some(Cmd::Set {
+                    key,
+                    value,
+                    expires_at: None,
+                }),
+                None if reads.get(&key) == Some(&None) => None,
+                None => Some(Cmd::Rm { key }),
+            })
+            .collect()
+    }
+
+    fn end(&mut self) {
+        if !self.ended {
+            self.ended = true;
+            self.versions.lock().unwrap().end(self.begin);
+        }
+    }
+}
+
+impl Drop for TxnState {
+    fn drop(&mut self) {
+        self.end();
+    }
+}
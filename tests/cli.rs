@@ -1,7 +1,13 @@
+#![allow(clippy::needless_borrows_for_generic_args, clippy::zombie_processes)]
+
 use assert_cmd::prelude::*;
+use kvs::{Request, Response};
 use predicates::str::{contains, is_empty};
+use serde::Deserialize;
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::{BufRead, BufReader};
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -322,6 +328,144 @@ fn cli_access_server(engine: &str, addr: &str) {
         .assert()
         .success()
         .stdout(contains("Key not found"));
+
+    for (key, value) in [("key3", "value4"), ("other", "value5")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", key, value, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2\tvalue3\nkey3\tvalue4\nother\tvalue5\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key3\tvalue4\nother\tvalue5\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "a", "key3", "--limit", "5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2\tvalue3\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--prefix", "key", "--limit", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2\tvalue3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "key3", "--expected", "value4", "--new", "value6"])
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "key3", "--expected", "value4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("value6"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "key3", "--expected", "value6", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key4", "value7", "--ttl", "60", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "key4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("60"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["persist", "key4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "key4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("No expiry"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["incr", "counter", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["incr", "counter", "-5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("-4\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["incr", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("not an integer"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["append", "key2", "_suffix", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value3_suffix\n");
     sender.send(()).unwrap();
     handle.join().unwrap();
 }
@@ -335,3 +479,317 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+/// Sends `request` over `stream` and reads the response, leaving the
+/// connection open for the next request.
+fn round_trip(stream: &TcpStream, request: &Request) -> Response {
+    serde_json::to_writer(stream, request).unwrap();
+    let mut responses = serde_json::Deserializer::from_reader(stream);
+    Response::deserialize(&mut responses).unwrap()
+}
+
+// Keys and values go over the wire as strings where they are UTF-8, and
+// as arrays of bytes otherwise
+#[test]
+fn wire_format() {
+    let set = Request::Set {
+        key: b"key1".to_vec(),
+        value: vec![0xff, 0],
+    };
+    let json = serde_json::to_string(&set).unwrap();
+    assert_eq!(json, r#"{"Set":{"key":"key1","value":[255,0]}}"#);
+    match serde_json::from_str(&json).unwrap() {
+        Request::Set { key, value } => {
+            assert_eq!(key, b"key1");
+            assert_eq!(value, [0xff, 0]);
+        }
+        _ => panic!("unexpected request"),
+    }
+
+    let response = Response::Pairs(vec![(b"key1".to_vec(), b"value1".to_vec())]);
+    let json = serde_json::to_string(&response).unwrap();
+    assert_eq!(json, r#"{"Pairs":[["key1","value1"]]}"#);
+    let response = Response::Ok(Some(b"value1".to_vec()));
+    assert_eq!(
+        serde_json::to_string(&response).unwrap(),
+        r#"{"Ok":"value1"}"#
+    );
+    match serde_json::from_str(r#"{"Ok":[118,49]}"#).unwrap() {
+        Response::Ok(value) => assert_eq!(value, Some(b"v1".to_vec())),
+        _ => panic!("unexpected response"),
+    }
+}
+
+// Transactions live on the connection that began them and fail to commit
+// after a conflicting write
+fn server_transactions(engine: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let conn1 = TcpStream::connect(addr).unwrap();
+    let conn2 = TcpStream::connect(addr).unwrap();
+    let key = || b"key1".to_vec();
+    let get = |conn: &TcpStream| match round_trip(conn, &Request::Get { key: key() }) {
+        Response::Ok(value) => value,
+        _ => panic!("unexpected response"),
+    };
+
+    let txn = match round_trip(&conn1, &Request::Begin) {
+        Response::Transaction(txn) => txn,
+        _ => panic!("expected a transaction id"),
+    };
+    let read = round_trip(&conn1, &Request::TxnGet { txn, key: key() });
+    assert!(matches!(read, Response::Ok(None)));
+    let set = Request::TxnSet {
+        txn,
+        key: key(),
+        value: b"value1".to_vec(),
+    };
+    assert!(matches!(round_trip(&conn1, &set), Response::Ok(None)));
+    assert_eq!(get(&conn2), None);
+    // the id means nothing on another connection
+    let commit = round_trip(&conn2, &Request::Commit { txn });
+    assert!(matches!(commit, Response::Err(e) if e.contains("unknown transaction")));
+    let commit = round_trip(&conn1, &Request::Commit { txn });
+    assert!(matches!(commit, Response::Ok(None)));
+    assert_eq!(get(&conn2), Some(b"value1".to_vec()));
+
+    let txn = match round_trip(&conn1, &Request::Begin) {
+        Response::Transaction(txn) => txn,
+        _ => panic!("expected a transaction id"),
+    };
+    round_trip(&conn1, &Request::TxnGet { txn, key: key() });
+    let set = Request::Set {
+        key: key(),
+        value: b"value2".to_vec(),
+    };
+    assert!(matches!(round_trip(&conn2, &set), Response::Ok(None)));
+    let set = Request::TxnSet {
+        txn,
+        key: key(),
+        value: b"value3".to_vec(),
+    };
+    round_trip(&conn1, &set);
+    let commit = round_trip(&conn1, &Request::Commit { txn });
+    assert!(matches!(commit, Response::Err(e) if e.contains("conflict")));
+    assert_eq!(get(&conn1), Some(b"value2".to_vec()));
+
+    let txn = match round_trip(&conn1, &Request::Begin) {
+        Response::Transaction(txn) => txn,
+        _ => panic!("expected a transaction id"),
+    };
+    round_trip(&conn1, &Request::TxnRemove { txn, key: key() });
+    let rollback = round_trip(&conn1, &Request::Rollback { txn });
+    assert!(matches!(rollback, Response::Ok(None)));
+    assert_eq!(get(&conn1), Some(b"value2".to_vec()));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait for server");
+}
+
+#[test]
+fn server_transactions_kvs_engine() {
+    server_transactions("kvs", "127.0.0.1:4006");
+}
+
+#[test]
+fn server_transactions_sled_engine() {
+    server_transactions("sled", "127.0.0.1:4007");
+}
+
+// `kvs-client watch` prints the changes to keys with its prefix as they
+// are made
+fn server_watch(engine: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut watch = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["watch", "user/", "--addr", addr])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let (sender, receiver) = mpsc::channel();
+    let stdout = BufReader::new(watch.stdout.take().unwrap());
+    thread::spawn(move || {
+        for line in stdout.lines() {
+            sender.send(line.unwrap()).unwrap();
+        }
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .assert()
+            .success();
+    };
+    client(&["set", "user/1", "alice"]);
+    client(&["set", "group/1", "admins"]);
+    client(&["append", "user/1", "_smith"]);
+    client(&["rm", "user/1"]);
+    let lines: Vec<String> = (0..3)
+        .map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap())
+        .collect();
+    assert_eq!(
+        lines,
+        [
+            "set\tuser/1\talice",
+            "set\tuser/1\talice_smith",
+            "rm\tuser/1"
+        ]
+    );
+
+    watch.kill().expect("watch exited before killed");
+    watch.wait().expect("failed to wait for watch");
+    server.kill().expect("server exited before killed");
+    server.wait().expect("failed to wait for server");
+}
+
+#[test]
+fn server_watch_kvs_engine() {
+    server_watch("kvs", "127.0.0.1:4008");
+}
+
+#[test]
+fn server_watch_sled_engine() {
+    server_watch("sled", "127.0.0.1:4009");
+}
+
+// `--namespace` sends a request to a tree of the store
+fn server_namespaces(engine: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .assert()
+            .success()
+    };
+    client(&["set", "key1", "default"]);
+    client(&["set", "key1", "alice", "--namespace", "users"]);
+    client(&["get", "key1"]).stdout("default\n");
+    client(&["get", "key1", "--namespace", "users"]).stdout("alice\n");
+    client(&["scan", "--namespace", "users"]).stdout("key1\talice\n");
+    // reading a namespace does not create it, writing does
+    for _ in 0..2 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "key1", "--namespace", "groups", "--addr", addr])
+            .assert()
+            .failure()
+            .stderr(contains("no such namespace"));
+    }
+    client(&["set", "key2", "admins", "--namespace", "groups"]);
+    client(&["get", "key1", "--namespace", "groups"]).stdout("Key not found\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--namespace", "a/b", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("invalid tree name"));
+
+    // a transaction belongs to the namespace it was begun in
+    let conn = TcpStream::connect(addr).unwrap();
+    let in_users = |request: Request| Request::Tree {
+        name: "users".to_owned(),
+        request: Box::new(request),
+    };
+    let txn = match round_trip(&conn, &in_users(Request::Begin)) {
+        Response::Transaction(txn) => txn,
+        _ => panic!("expected a transaction id"),
+    };
+    let read = |request: Request| match round_trip(&conn, &request) {
+        Response::Ok(value) => Ok(value),
+        Response::Err(e) => Err(e),
+        _ => panic!("unexpected response"),
+    };
+    let get = |key: &[u8]| Request::TxnGet {
+        txn,
+        key: key.to_vec(),
+    };
+    let other = Request::Tree {
+        name: "groups".to_owned(),
+        request: Box::new(get(b"key2")),
+    };
+    assert!(
+        read(get(b"key1"))
+            .unwrap_err()
+            .contains("unknown transaction")
+    );
+    assert!(read(other).unwrap_err().contains("unknown transaction"));
+    assert_eq!(read(in_users(get(b"key1"))), Ok(Some(b"alice".to_vec())));
+
+    server.kill().expect("server exited before killed");
+    server.wait().expect("failed to wait for server");
+}
+
+#[test]
+fn server_namespaces_kvs_engine() {
+    server_namespaces("kvs", "127.0.0.1:4010");
+}
+
+#[test]
+fn server_namespaces_sled_engine() {
+    server_namespaces("sled", "127.0.0.1:4011");
+}
+
+// A server serves a bounded number of connections at once and turns away
+// the ones beyond until one of them closes
+#[test]
+fn server_connection_limit() {
+    let addr = "127.0.0.1:4012";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let get = Request::Get {
+        key: b"key1".to_vec(),
+    };
+    let mut conns: Vec<_> = (0..512)
+        .map(|_| {
+            let conn = TcpStream::connect(addr).unwrap();
+            assert!(matches!(round_trip(&conn, &get), Response::Ok(None)));
+            conn
+        })
+        .collect();
+    // turned away before it sends anything
+    let conn = TcpStream::connect(addr).unwrap();
+    let refused = Response::deserialize(&mut serde_json::Deserializer::from_reader(&conn)).unwrap();
+    assert!(matches!(refused, Response::Err(e) if e.contains("too many connections")));
+
+    conns.pop();
+    thread::sleep(Duration::from_millis(500));
+    let conn = TcpStream::connect(addr).unwrap();
+    assert!(matches!(round_trip(&conn, &get), Response::Ok(None)));
+
+    child.kill().expect("server exited before killed");
+}
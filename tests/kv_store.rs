@@ -1,11 +1,42 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    CasOutcome, EncryptionKey, Event, KvStore, KvsEngine, KvsError, KvsSnapshot, KvsTransaction,
+    KvsWatcher, LOG_HEADER_LEN, Op, Result, ScanIter, SyncPolicy, WriteBatch,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
+// The files in `dir` named `*.ext`, numbered ones oldest first
+fn list_files(dir: &std::path::Path, ext: &str) -> Vec<std::path::PathBuf> {
+    let mut paths: Vec<_> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == ext))
+        .collect();
+    paths.sort_by_key(|path| {
+        let id = path
+            .file_stem()
+            .and_then(|stem| stem.to_str()?.parse::<u64>().ok());
+        (id, path.clone())
+    });
+    paths
+}
+
+// Waits until compactions have left `count` hint files in `dir`
+fn wait_for_hints(dir: &std::path::Path, count: usize) {
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while list_files(dir, "hint").len() < count {
+        assert!(Instant::now() < deadline, "No compaction detected");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
 // Should get previously stored value
 #[test]
+#[allow(clippy::unnecessary_to_owned)]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
@@ -27,6 +58,7 @@ fn get_stored_value() -> Result<()> {
 
 // Should overwrite existent value
 #[test]
+#[allow(clippy::unnecessary_to_owned)]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
@@ -48,6 +80,7 @@ fn overwrite_value() -> Result<()> {
 
 // Should get `None` when getting a non-existent key
 #[test]
+#[allow(clippy::unnecessary_to_owned)]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
@@ -64,6 +97,7 @@ fn get_non_existent_value() -> Result<()> {
 }
 
 #[test]
+#[allow(clippy::unnecessary_to_owned)]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
@@ -72,6 +106,7 @@ fn remove_non_existent_key() -> Result<()> {
 }
 
 #[test]
+#[allow(clippy::unnecessary_to_owned)]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
@@ -82,21 +117,33 @@ fn remove_key() -> Result<()> {
 }
 
 // Insert data until total size of the directory decreases.
-// Test data correctness after compaction.
+// Test data correctness after compaction, and that the file without
+// garbage is left alone.
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("static{}", key_id), "value")?;
+    }
+    drop(store);
+    let static_log = list_files(temp_dir.path(), "log").remove(0);
+    let static_contents = std::fs::read(&static_log)?;
+    let store = KvStore::open(temp_dir.path())?;
+    let stats = store.file_stats();
+    assert_eq!(
+        stats[0].bytes,
+        static_contents.len() as u64 - LOG_HEADER_LEN
+    );
+    assert_eq!(stats[0].dead_bytes, 0);
 
-    let dir_size = || {
-        let entries = WalkDir::new(temp_dir.path()).into_iter();
-        let len: walkdir::Result<u64> = entries
-            .map(|res| {
-                res.and_then(|entry| entry.metadata())
-                    .map(|metadata| metadata.len())
-            })
-            .sum();
-        len.expect("fail to get directory size")
+    // Files a running compaction deletes may vanish while they are listed
+    let dir_size = || -> u64 {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok()?.metadata().ok())
+            .map(|metadata| metadata.len())
+            .sum()
     };
 
     let mut current_size = dir_size();
@@ -107,20 +154,24 @@ fn compaction() -> Result<()> {
             store.set(key, value)?;
         }
 
-        let new_size = dir_size();
-        if new_size > current_size {
-            current_size = new_size;
+        if list_files(temp_dir.path(), "hint").is_empty() {
+            current_size = current_size.max(dir_size());
             continue;
         }
-        // Compaction triggered
-
+        // Compaction triggered, dropping the store waits for it to finish
         drop(store);
+        assert!(dir_size() < current_size);
+        assert_eq!(std::fs::read(&static_log)?, static_contents);
         // reopen and check content
         let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
         }
+        for key_id in 0..100 {
+            let key = format!("static{}", key_id);
+            assert_eq!(store.get(key)?, Some("value".to_owned()));
+        }
         return Ok(());
     }
 
@@ -209,3 +260,1725 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Reads run while a writer keeps rotating and compacting the log under
+// them, and always see a complete value
+#[test]
+fn concurrent_get_during_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path())
+        .max_file_size(4096)
+        .compaction_threshold(16 * 1024)
+        .open()?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let done = AtomicBool::new(false);
+    thread::scope(|s| {
+        for thread_id in 0..8 {
+            let (store, done) = (&store, &done);
+            s.spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    for i in 0..100 {
+                        let key_id = (i + thread_id) % 100;
+                        assert_eq!(
+                            store.get(format!("key{}", key_id)).unwrap(),
+                            Some(format!("value{}", key_id))
+                        );
+                    }
+                }
+            });
+        }
+        for iter in 0..20 {
+            for i in 0..100 {
+                store
+                    .set(format!("key{}", i), format!("value{}", i))
+                    .unwrap();
+                store
+                    .set(format!("other{}", i), format!("{}", iter))
+                    .unwrap();
+            }
+        }
+        done.store(true, Ordering::SeqCst);
+    });
+    assert!(!list_files(temp_dir.path(), "hint").is_empty());
+    Ok(())
+}
+
+// Values may contain newlines now that records are length-prefixed
+#[test]
+fn value_with_newlines() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "line1\nline2\n")?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("line1\nline2\n".to_owned()));
+    Ok(())
+}
+
+// Logs written in the old JSON-lines format are migrated on open
+#[test]
+fn migrate_legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("1.log"),
+        concat!(
+            "{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}\n",
+            "{\"Set\":{\"key\":\"key2\",\"value\":\"value2\"}}\n",
+            "{\"Rm\":{\"key\":\"key1\"}}\n",
+        ),
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    store.set("key3", "value3")?;
+
+    drop(store);
+    assert!(!std::fs::read(temp_dir.path().join("1.log"))?.starts_with(b"{"));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A crash may leave the last line of the newest legacy log torn, which is
+// dropped; anywhere else, an unreadable line fails the open
+#[test]
+fn migrate_torn_legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let lines = concat!(
+        "{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}\n",
+        "{\"Set\":{\"key\":\"key2\",\"value\":\"value2\"}}\n",
+        "{\"Set\":{\"key\":\"key3\",\"va",
+    );
+    std::fs::write(temp_dir.path().join("1.log"), lines)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, None);
+    store.set("key3", "value3")?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(temp_dir.path().join("1.log"), lines)?;
+    std::fs::write(
+        temp_dir.path().join("2.log"),
+        "{\"Rm\":{\"key\":\"key1\"}}\n",
+    )?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    Ok(())
+}
+
+#[test]
+fn reject_unknown_log_format() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(temp_dir.path().join("1.log"), b"not a kvs log").unwrap();
+    assert!(KvStore::open(temp_dir.path()).is_err());
+}
+
+// A partially written last record is dropped on open instead of failing
+#[test]
+fn recover_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    drop(store);
+
+    let log = list_files(temp_dir.path(), "log").pop().unwrap();
+    let len = std::fs::metadata(&log)?.len();
+    let file = std::fs::OpenOptions::new().write(true).open(&log)?;
+    file.set_len(len - 3)?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    store.set("key2", "value3")?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A record whose checksum does not match is treated like a torn write
+#[test]
+fn recover_corrupt_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    drop(store);
+
+    let log = list_files(temp_dir.path(), "log").pop().unwrap();
+    let mut bytes = std::fs::read(&log)?;
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    std::fs::write(&log, &bytes)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    assert!(std::fs::metadata(&log)?.len() < bytes.len() as u64);
+    Ok(())
+}
+
+// A bad record in a log file that is no longer written to cannot be a torn
+// write, so open fails instead of dropping the rest of the file
+#[test]
+fn reject_corrupt_older_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3", "value3")?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let mut bytes = std::fs::read(&log)?;
+    bytes[20] ^= 0xff;
+    std::fs::write(&log, &bytes)?;
+
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    assert!(
+        KvStore::builder(temp_dir.path())
+            .read_only(true)
+            .open()
+            .is_err()
+    );
+    assert_eq!(std::fs::read(&log)?, bytes);
+    Ok(())
+}
+
+// Compaction leaves a hint file behind that open uses instead of replaying
+// the compacted log; a damaged hint falls back to replay.
+#[test]
+fn compaction_writes_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let mut iter = 0;
+    while list_files(temp_dir.path(), "hint").is_empty() {
+        iter += 1;
+        assert!(iter < 1000, "No compaction detected");
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}", iter))
+        );
+    }
+
+    drop(store);
+    for hint in list_files(temp_dir.path(), "hint") {
+        std::fs::write(hint, b"garbage")?;
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}", iter))
+        );
+    }
+    Ok(())
+}
+
+// Writes keep going while compaction runs in the background; none of them
+// may be lost when the index is switched over to the compacted file.
+#[test]
+fn concurrent_set_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for iter in 0..200 {
+                for key_id in 0..50 {
+                    store
+                        .set(
+                            format!("key{}_{}", thread_id, key_id),
+                            format!("{:0>100}", iter),
+                        )
+                        .unwrap();
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for thread_id in 0..8 {
+            for key_id in 0..50 {
+                assert_eq!(
+                    store.get(format!("key{}_{}", thread_id, key_id))?,
+                    Some(format!("{:0>100}", 199))
+                );
+            }
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)
+}
+
+// The active file is rotated once it reaches the configured size, and
+// compaction splits its output at the same size
+#[test]
+fn builder_max_file_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path())
+        .max_file_size(1024)
+        .open()?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert!(list_files(temp_dir.path(), "log").len() > 1);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    // some garbage in every file, for one compaction to take them all
+    for i in (0..100).step_by(5) {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let store = KvStore::builder(temp_dir.path())
+        .max_file_size(1024)
+        .compaction_threshold(1)
+        .file_dead_ratio(0.0)
+        .open()?;
+    store.set("key0", "value0")?;
+    wait_for_hints(temp_dir.path(), 2);
+    drop(store);
+    for hint in list_files(temp_dir.path(), "hint") {
+        assert!(std::fs::metadata(hint.with_extension("log"))?.len() < 1024 + 64);
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert!(
+        KvStore::builder(temp_dir.path())
+            .file_dead_ratio(1.5)
+            .open()
+            .is_err()
+    );
+    Ok(())
+}
+
+// A low dead-bytes ratio compacts a store far below the byte threshold
+#[test]
+fn builder_dead_ratio() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path()).dead_ratio(0.5).open()?;
+    for iter in 0..10 {
+        store.set("key1", format!("value{}", iter))?;
+    }
+    drop(store);
+    assert!(!list_files(temp_dir.path(), "hint").is_empty());
+
+    assert!(
+        KvStore::builder(temp_dir.path())
+            .dead_ratio(1.5)
+            .open()
+            .is_err()
+    );
+    Ok(())
+}
+
+#[test]
+fn builder_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    drop(store);
+    let files = list_files(temp_dir.path(), "log").len();
+
+    let store = KvStore::builder(temp_dir.path()).read_only(true).open()?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert!(store.set("key2", "value2").is_err());
+    assert!(store.remove("key1").is_err());
+    assert_eq!(list_files(temp_dir.path(), "log").len(), files);
+    drop(store);
+
+    // without a lock file there is no writer, and none is created
+    let lock = temp_dir.path().join("LOCK");
+    std::fs::remove_file(&lock)?;
+    let store = KvStore::builder(temp_dir.path()).read_only(true).open()?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert!(!lock.exists());
+    Ok(())
+}
+
+// Every sync policy keeps concurrent writes intact, also across rotations
+#[test]
+fn sync_policies() -> Result<()> {
+    let policies = [
+        SyncPolicy::Never,
+        SyncPolicy::Always,
+        SyncPolicy::GroupCommit,
+        SyncPolicy::Interval(Duration::from_millis(5)),
+    ];
+    for policy in policies {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::builder(temp_dir.path())
+            .sync_policy(policy)
+            .max_file_size(1024)
+            .open()?;
+        thread::scope(|s| {
+            for t in 0..4 {
+                let store = store.clone();
+                s.spawn(move || {
+                    for i in 0..50 {
+                        store
+                            .set(format!("key{}_{}", t, i), format!("value{}", i))
+                            .unwrap();
+                    }
+                });
+            }
+        });
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        for t in 0..4 {
+            for i in 0..50 {
+                assert_eq!(
+                    store.get(format!("key{}_{}", t, i))?,
+                    Some(format!("value{}", i))
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn parse_sync_policy() {
+    assert_eq!("never".parse(), Ok(SyncPolicy::Never));
+    assert_eq!("always".parse(), Ok(SyncPolicy::Always));
+    assert_eq!("group-commit".parse(), Ok(SyncPolicy::GroupCommit));
+    assert_eq!(
+        "interval:250".parse(),
+        Ok(SyncPolicy::Interval(Duration::from_millis(250)))
+    );
+    assert!("interval:soon".parse::<SyncPolicy>().is_err());
+}
+
+// Only one read-write open of a directory at a time; read-only opens share
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+
+    let err = KvStore::open(temp_dir.path())
+        .err()
+        .expect("second open succeeded");
+    assert!(matches!(
+        err.downcast_ref::<KvsError>(),
+        Some(KvsError::Locked(_))
+    ));
+    assert!(
+        KvStore::builder(temp_dir.path())
+            .read_only(true)
+            .open()
+            .is_err()
+    );
+
+    drop(store);
+    let reader1 = KvStore::builder(temp_dir.path()).read_only(true).open()?;
+    let reader2 = KvStore::builder(temp_dir.path()).read_only(true).open()?;
+    assert_eq!(reader2.get("key1")?, Some("value1".to_owned()));
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    drop(reader1);
+    drop(reader2);
+    KvStore::open(temp_dir.path())?;
+    Ok(())
+}
+
+fn collect(pairs: ScanIter) -> Result<Vec<(String, String)>> {
+    pairs
+        .map(|pair| {
+            let (key, value) = pair?;
+            Ok((String::from_utf8(key)?, String::from_utf8(value)?))
+        })
+        .collect()
+}
+
+fn pairs(keys: &[&str]) -> Vec<(String, String)> {
+    keys.iter()
+        .map(|key| (key.to_string(), format!("value_{}", key)))
+        .collect()
+}
+
+// Scans return pairs in key order, also after compaction and reopen
+#[test]
+fn scan_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path()).dead_ratio(0.3).open()?;
+    for key in ["d", "b", "a", "e", "c"] {
+        store.set(key.to_owned(), format!("value_{}", key))?;
+    }
+    store.remove("c")?;
+
+    assert_eq!(
+        collect(store.scan(.., None)?)?,
+        pairs(&["a", "b", "d", "e"])
+    );
+    assert_eq!(
+        collect(store.scan(b"b".to_vec()..b"e".to_vec(), None)?)?,
+        pairs(&["b", "d"])
+    );
+    assert_eq!(
+        collect(store.scan(b"b".to_vec().., Some(2))?)?,
+        pairs(&["b", "d"])
+    );
+    assert_eq!(
+        collect(store.scan(b"e".to_vec()..b"b".to_vec(), None)?)?,
+        []
+    );
+
+    for _ in 0..10 {
+        store.set("b", "value_b")?;
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        collect(store.scan(.., None)?)?,
+        pairs(&["a", "b", "d", "e"])
+    );
+    Ok(())
+}
+
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in ["user:2", "user", "user:1", "users", "admin:1", "v"] {
+        store.set(key.to_owned(), format!("value_{}", key))?;
+    }
+    assert_eq!(
+        collect(store.scan_prefix("user:")?)?,
+        pairs(&["user:1", "user:2"])
+    );
+    assert_eq!(
+        collect(store.scan_prefix("user")?)?,
+        pairs(&["user", "user:1", "user:2", "users"])
+    );
+    assert_eq!(collect(store.scan_prefix("x")?)?, []);
+    Ok(())
+}
+
+// A snapshot keeps seeing the store as it was, even across compaction, and
+// keeps the files it reads from until it is dropped
+#[test]
+fn snapshot_isolation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path()).dead_ratio(0.5).open()?;
+    for key in ["key1", "key2", "key3"] {
+        store.set(key.to_owned(), format!("value_{}", key))?;
+    }
+    let snapshot = store.snapshot()?;
+
+    for iter in 0..10 {
+        store.set("key1", format!("value{}", iter))?;
+    }
+    store.remove("key2")?;
+    store.set("key4", "value_key4")?;
+    assert_eq!(store.get("key1")?, Some("value9".to_owned()));
+    drop(store);
+
+    assert_eq!(snapshot.get("key1")?, Some("value_key1".to_owned()));
+    assert_eq!(snapshot.get("key2")?, Some("value_key2".to_owned()));
+    assert_eq!(snapshot.get("key4")?, None);
+    assert_eq!(
+        collect(snapshot.scan(.., None)?)?,
+        pairs(&["key1", "key2", "key3"])
+    );
+    assert_eq!(
+        collect(snapshot.scan_prefix("key")?)?,
+        pairs(&["key1", "key2", "key3"])
+    );
+
+    let files = list_files(temp_dir.path(), "log").len();
+    drop(snapshot);
+    assert!(list_files(temp_dir.path(), "log").len() < files);
+    Ok(())
+}
+
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2", "value2")
+        .set("key3", "value3")
+        .remove("key1")
+        .remove("key3")
+        .remove("missing");
+    store.write_batch(batch)?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, None);
+    Ok(())
+}
+
+// Unlike a single remove, a batch takes removes of keys that do not exist
+// or have expired, and applies the rest of it
+#[test]
+fn write_batch_remove_missing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl("expired", "value", Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(10));
+    assert!(store.remove("missing").is_err());
+    assert!(store.remove("expired").is_err());
+
+    for key in ["missing", "expired"] {
+        let mut batch = WriteBatch::new();
+        batch.remove(key).set(format!("after_{}", key), "value");
+        store.write_batch(batch)?;
+        assert_eq!(store.get(key)?, None);
+        assert_eq!(
+            store.get(format!("after_{}", key))?,
+            Some("value".to_owned())
+        );
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("expired")?, None);
+    assert_eq!(store.get("after_expired")?, Some("value".to_owned()));
+    Ok(())
+}
+
+// A batch whose last record was torn is dropped as a whole on open
+#[test]
+fn recover_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2", "value2")
+        .set("key3", "value3")
+        .remove("key1");
+    store.write_batch(batch)?;
+    drop(store);
+
+    let log = list_files(temp_dir.path(), "log").pop().unwrap();
+    let len = std::fs::metadata(&log)?.len();
+    let file = std::fs::OpenOptions::new().write(true).open(&log)?;
+    file.set_len(len - 3)?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get("key3")?, None);
+    store.set("key2", "value4")?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2")?, Some("value4".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let swap = |expected: Option<&str>, new: Option<&str>| {
+        store.compare_and_swap("key1", expected.map(Vec::from), new.map(Vec::from))
+    };
+    assert_eq!(swap(None, Some("value1"))?, CasOutcome::Swapped);
+    assert_eq!(
+        swap(None, Some("value2"))?,
+        CasOutcome::Conflict(Some(b"value1".to_vec()))
+    );
+    assert_eq!(swap(Some("value1"), Some("value2"))?, CasOutcome::Swapped);
+    assert_eq!(swap(Some("value2"), None)?, CasOutcome::Swapped);
+    assert_eq!(swap(Some("value2"), None)?, CasOutcome::Conflict(None));
+    assert_eq!(store.get("key1")?, None);
+    Ok(())
+}
+
+// A swap keeps the expiry time of the key it replaces, but not of one that
+// has expired already
+#[test]
+fn compare_and_swap_keeps_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl("key1", "value1", Duration::from_secs(60))?;
+    store.set_with_ttl("key2", "value1", Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(10));
+
+    let outcome =
+        store.compare_and_swap("key1", Some(b"value1".to_vec()), Some(b"value2".to_vec()))?;
+    assert_eq!(outcome, CasOutcome::Swapped);
+    assert!(store.ttl("key1")?.is_some());
+    let outcome = store.compare_and_swap("key2", None, Some(b"value2".to_vec()))?;
+    assert_eq!(outcome, CasOutcome::Swapped);
+    assert_eq!(store.ttl("key2")?, None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+    assert!(store.ttl("key1")?.is_some());
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Increments made through compare-and-swap from many threads are not lost
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter", "0")?;
+    thread::scope(|s| {
+        for _ in 0..8 {
+            let store = store.clone();
+            s.spawn(move || {
+                for _ in 0..50 {
+                    loop {
+                        let current = store.get("counter").unwrap().unwrap();
+                        let next = (current.parse::<u64>().unwrap() + 1).to_string();
+                        let outcome = store
+                            .compare_and_swap(
+                                "counter",
+                                Some(current.into_bytes()),
+                                Some(next.into_bytes()),
+                            )
+                            .unwrap();
+                        if outcome == CasOutcome::Swapped {
+                            break;
+                        }
+                    }
+                }
+            });
+        }
+    });
+    assert_eq!(store.get("counter")?, Some("400".to_owned()));
+    Ok(())
+}
+
+// Keys set with a TTL disappear once it runs out, also after a reopen
+#[test]
+fn key_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1", "value1")?;
+    store.set_with_ttl("key2", "value2", Duration::from_millis(200))?;
+    store.set_with_ttl("key3", "value3", Duration::from_secs(60))?;
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.ttl("key1")?, None);
+    let ttl = store.ttl("key3")?.expect("key3 has no ttl");
+    assert!(ttl > Duration::from_secs(50) && ttl <= Duration::from_secs(60));
+
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("key2")?, None);
+    assert!(store.ttl("key2").is_err());
+    assert!(store.remove("key2").is_err());
+    assert_eq!(
+        collect(store.scan(.., None)?)?,
+        vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key3".to_owned(), "value3".to_owned()),
+        ]
+    );
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    assert!(store.ttl("key3")?.is_some());
+    Ok(())
+}
+
+// `persist` keeps a key for good, and plain `set` clears an earlier TTL
+#[test]
+fn persist_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set_with_ttl("key1", "value1", Duration::from_millis(200))?;
+    store.set_with_ttl("key2", "value2", Duration::from_millis(200))?;
+    store.persist("key1")?;
+    store.set("key2", "value3")?;
+    assert_eq!(store.ttl("key1")?, None);
+    assert!(store.persist("key3").is_err());
+
+    std::thread::sleep(Duration::from_millis(300));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Compaction leaves expired keys behind instead of copying them
+#[test]
+fn compaction_drops_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path())
+        .reap_interval(Duration::from_secs(3600))
+        .open()?;
+
+    for key_id in 0..100 {
+        store.set_with_ttl(
+            format!("key{}", key_id),
+            "value",
+            Duration::from_millis(100),
+        )?;
+    }
+    std::thread::sleep(Duration::from_millis(200));
+
+    let mut iter = 0;
+    while list_files(temp_dir.path(), "hint").is_empty() {
+        iter += 1;
+        assert!(iter < 1000, "No compaction detected");
+        for key_id in 0..1000 {
+            store.set(format!("other{}", key_id), format!("{}", iter))?;
+        }
+    }
+    drop(store);
+
+    for hint in list_files(temp_dir.path(), "hint") {
+        std::fs::remove_file(hint)?;
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(collect(store.scan_prefix("key")?)?, vec![]);
+    for log in std::fs::read_dir(temp_dir.path())? {
+        let contents = std::fs::read(log?.path())?;
+        assert!(!contents.windows(5).any(|window| window == b"key99"));
+    }
+    Ok(())
+}
+
+// Keys and values are arbitrary bytes, also across compaction and reopen
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let key = vec![0xff, 0x00, 0xfe];
+    let value = vec![0x80, 0x00, 0x81, 0xc3];
+    store.set(key.clone(), value.clone())?;
+    store.set(b"\xff\x01".to_vec(), b"other".to_vec())?;
+    assert_eq!(store.get_bytes(&key)?, Some(value.clone()));
+    assert!(store.get(&key).is_err());
+    let scanned = store.scan_prefix([0xff])?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        scanned,
+        vec![
+            (key.clone(), value.clone()),
+            (b"\xff\x01".to_vec(), b"other".to_vec()),
+        ]
+    );
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(&key)?, Some(value));
+    store.remove(&key)?;
+    assert_eq!(store.get_bytes(&key)?, None);
+    Ok(())
+}
+
+// A transaction sees its own writes, which nobody else sees before commit
+#[test]
+fn transaction_commit_and_rollback() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+
+    let mut txn = store.begin()?;
+    assert_eq!(txn.get_bytes("key1")?, Some(b"value1".to_vec()));
+    txn.set("key1", "value2")?;
+    txn.set("key2", "value3")?;
+    assert_eq!(txn.get_bytes("key1")?, Some(b"value2".to_vec()));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    txn.commit()?;
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value3".to_owned()));
+
+    let mut txn = store.begin()?;
+    txn.remove("key1")?;
+    assert_eq!(txn.get_bytes("key1")?, None);
+    assert!(txn.remove("key1").is_err());
+    txn.rollback();
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Committing fails if a key the transaction read was written in between
+#[test]
+fn transaction_conflict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+
+    let mut txn = store.begin()?;
+    txn.get("key1")?;
+    txn.set("key2", "value2")?;
+    store.set("key1", "other")?;
+    match txn.commit() {
+        Err(e) => assert!(matches!(e.downcast_ref(), Some(KvsError::Conflict))),
+        Ok(()) => panic!("commit should conflict"),
+    }
+    assert_eq!(store.get("key2")?, None);
+
+    // untouched keys don't conflict
+    let mut txn = store.begin()?;
+    txn.set("key2", "value2")?;
+    store.set("key1", "again")?;
+    txn.commit()?;
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Counters start at 0 and keep their expiry; non-numbers are rejected
+#[test]
+fn incr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.incr("counter", 5)?, 5);
+    assert_eq!(store.incr("counter", -7)?, -2);
+    assert_eq!(store.get("counter")?, Some("-2".to_owned()));
+    store.set("text", "abc")?;
+    assert!(store.incr("text", 1).is_err());
+    store.set("max", i64::MAX.to_string())?;
+    assert!(store.incr("max", 1).is_err());
+
+    store.set_with_ttl("temp", "1", Duration::from_secs(60))?;
+    assert_eq!(store.incr("temp", 1)?, 2);
+    assert!(store.ttl("temp")?.is_some());
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.incr("counter", 1)?, -1);
+    Ok(())
+}
+
+// Increments from many threads are not lost
+#[test]
+fn concurrent_incr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    thread::scope(|s| {
+        for _ in 0..8 {
+            let store = store.clone();
+            s.spawn(move || {
+                for _ in 0..50 {
+                    store.incr("counter", 1).unwrap();
+                }
+            });
+        }
+    });
+    assert_eq!(store.get("counter")?, Some("400".to_owned()));
+    Ok(())
+}
+
+// Merge operands are resolved on read, across reopen and by compaction,
+// including while compaction runs in the background
+#[test]
+fn merge_operators() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let max = |_: &[u8], existing: Option<&[u8]>, operand: &[u8]| {
+        existing
+            .map_or(operand, |existing| existing.max(operand))
+            .to_vec()
+    };
+    let store = KvStore::builder(temp_dir.path())
+        .compaction_threshold(4 * 1024)
+        .open()?;
+    store.register_merge_operator("max", max);
+
+    store.append("log", "a")?;
+    store.append("log", "b")?;
+    store.merge("high", "max", "3")?;
+    store.merge("high", "max", "7")?;
+    store.merge("high", "max", "5")?;
+    assert_eq!(store.get("log")?, Some("ab".to_owned()));
+    assert_eq!(store.get("high")?, Some("7".to_owned()));
+    assert!(store.merge("high", "unknown", "1").is_err());
+    let mut batch = WriteBatch::new();
+    batch.set("log", "x").merge("log", "append", "y");
+    store.write_batch(batch)?;
+    assert_eq!(store.get("log")?, Some("xy".to_owned()));
+
+    drop(store);
+    let store = KvStore::builder(temp_dir.path())
+        .compaction_threshold(4 * 1024)
+        .open()?;
+    assert!(store.get("high").is_err());
+    store.register_merge_operator("max", max);
+    assert_eq!(store.get("high")?, Some("7".to_owned()));
+
+    // enough appends to trigger several compactions on the way
+    let snapshot = store.snapshot()?;
+    for i in 0..2000 {
+        store.append("long", (i % 10).to_string())?;
+        store.set(format!("key{}", i % 20), "value")?;
+    }
+    let expected: String = (0..2000).map(|i| (i % 10).to_string()).collect();
+    assert_eq!(store.get("long")?, Some(expected.clone()));
+    assert_eq!(snapshot.get("log")?, Some("xy".to_owned()));
+    let scanned = store.scan_prefix("lo")?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        scanned[1],
+        (b"long".to_vec(), expected.clone().into_bytes())
+    );
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("long")?, Some(expected));
+    assert_eq!(store.get("log")?, Some("xy".to_owned()));
+    Ok(())
+}
+
+// Merging onto one key over and over resolves its chain of merge records
+// every so often and counts the operands it replaces as dead, so neither
+// reads nor the log grow without bound
+#[test]
+fn merge_chain_is_bounded() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let add = |_: &[u8], existing: Option<&[u8]>, operand: &[u8]| {
+        let parse = |bytes: &[u8]| std::str::from_utf8(bytes).unwrap().parse::<u64>().unwrap();
+        (existing.map_or(0, parse) + parse(operand))
+            .to_string()
+            .into_bytes()
+    };
+    let store = KvStore::builder(temp_dir.path())
+        .max_file_size(4 * 1024)
+        .compaction_threshold(16 * 1024)
+        .open()?;
+    store.register_merge_operator("add", add);
+    for _ in 0..5000 {
+        store.merge("counter", "add", "1")?;
+    }
+    assert_eq!(store.get("counter")?, Some("5000".to_owned()));
+    drop(store);
+
+    let logs = list_files(temp_dir.path(), "log");
+    let log_bytes: u64 = logs
+        .iter()
+        .map(|path| std::fs::metadata(path).unwrap().len())
+        .sum();
+    assert!(log_bytes < 64 * 1024, "{} bytes of logs", log_bytes);
+    // every merge record is read through, back to the last `Set`
+    let mut longest_chain = 0;
+    for path in logs {
+        let contents = std::fs::read(path)?;
+        let u32_at = |pos: usize| u32::from_le_bytes(contents[pos..pos + 4].try_into().unwrap());
+        let mut pos = LOG_HEADER_LEN as usize;
+        let mut chain = 0;
+        while pos < contents.len() {
+            let expires_len = if contents[pos + 13] & 2 != 0 { 8 } else { 0 };
+            chain = if contents[pos + 12] == 3 {
+                chain + 1
+            } else {
+                0
+            };
+            longest_chain = longest_chain.max(chain);
+            pos += 22 + expires_len + (u32_at(pos + 4) + u32_at(pos + 8)) as usize;
+        }
+    }
+    assert!(longest_chain <= 32, "a chain of {} merges", longest_chain);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.register_merge_operator("add", add);
+    assert_eq!(store.get("counter")?, Some("5000".to_owned()));
+    Ok(())
+}
+
+// With a memory budget far smaller than the index, most of it lives in run
+// files on disk; lookups, scans, compaction and reopening behave the same.
+#[test]
+fn memory_bounded_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder(temp_dir.path())
+            .index_memory(16 * 1024)
+            .compaction_threshold(64 * 1024)
+            .open()
+    };
+    let store = open()?;
+
+    for key_id in 0..5000 {
+        store.set(format!("key{:05}", key_id), format!("value{}", key_id))?;
+    }
+    assert!(!list_files(temp_dir.path(), "run").is_empty());
+    for key_id in (0..5000).step_by(3) {
+        store.remove(format!("key{:05}", key_id))?;
+    }
+    let expected = |key_id: usize| (!key_id.is_multiple_of(3)).then(|| format!("value{}", key_id));
+    for key_id in 0..5000 {
+        assert_eq!(store.get(format!("key{:05}", key_id))?, expected(key_id));
+    }
+    let pairs: Vec<_> = store
+        .scan(b"key01000".to_vec()..b"key01010".to_vec(), None)?
+        .collect::<Result<_>>()?;
+    let keys: Vec<_> = pairs.iter().map(|(key, _)| key.clone()).collect();
+    let want: Vec<_> = (1000..1010)
+        .filter(|key_id: &u32| !key_id.is_multiple_of(3))
+        .map(|key_id| format!("key{:05}", key_id).into_bytes())
+        .collect();
+    assert_eq!(keys, want);
+
+    // Overwrite a third of the keys until a compaction went through
+    let mut iter = 0;
+    loop {
+        iter += 1;
+        assert!(iter < 100, "No compaction detected");
+        for key_id in (0..5000).filter(|key_id| key_id % 3 == 1) {
+            store.set(format!("key{:05}", key_id), format!("{}", iter))?;
+        }
+        if !list_files(temp_dir.path(), "hint").is_empty() {
+            break;
+        }
+    }
+    let expected = |key_id: usize| match key_id % 3 {
+        0 => None,
+        1 => Some(format!("{}", iter)),
+        _ => Some(format!("value{}", key_id)),
+    };
+    for key_id in 0..5000 {
+        assert_eq!(store.get(format!("key{:05}", key_id))?, expected(key_id));
+    }
+
+    drop(store);
+    let store = open()?;
+    for key_id in 0..5000 {
+        assert_eq!(store.get(format!("key{:05}", key_id))?, expected(key_id));
+    }
+    assert_eq!(store.scan_prefix("key")?.count(), 5000 - 1667);
+    drop(store);
+    assert!(list_files(temp_dir.path(), "run").is_empty());
+    Ok(())
+}
+
+// Reading a key again is served from the value cache, which never returns
+// a value that was overwritten, removed or moved by compaction.
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path())
+        .value_cache(64 * 1024)
+        .open()?;
+
+    store.set("key1", "value1")?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    let stats = store.cache_stats();
+    assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+
+    store.set("key1", "value2")?;
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+    store.append("key1", "!")?;
+    assert_eq!(store.get("key1")?, Some("value2!".to_owned()));
+    store.remove("key1")?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.cache_stats().entries, 0);
+
+    // Hot keys are read back through compaction
+    let mut iter = 0;
+    while list_files(temp_dir.path(), "hint").is_empty() {
+        iter += 1;
+        assert!(iter < 1000, "No compaction detected");
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("{}", iter))
+            );
+        }
+    }
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}", iter))
+        );
+    }
+    let stats = store.cache_stats();
+    assert!(stats.bytes <= stats.capacity);
+    assert!(stats.hits > 0);
+    Ok(())
+}
+
+// Values above the threshold are stored compressed; logs mixing compressed
+// and uncompressed records read back the same with compression on or off,
+// and compaction compresses the old records.
+#[test]
+fn value_compression() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_size = || -> u64 {
+        list_files(temp_dir.path(), "log")
+            .iter()
+            .map(|path| std::fs::metadata(path).unwrap().len())
+            .sum()
+    };
+    let document = |id: usize| format!("{{\"id\":{},\"tags\":[{}]}}", id, "\"tag\",".repeat(50));
+
+    let store = KvStore::builder(temp_dir.path())
+        .compress_values(64)
+        .open()?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), document(key_id))?;
+    }
+    store.set("small", "tiny")?;
+    drop(store);
+    let compressed_size = log_size();
+    assert!(compressed_size < 100 * document(0).len() as u64 / 2);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 100..200 {
+        store.set(format!("key{}", key_id), document(key_id))?;
+    }
+    for key_id in 0..200 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(document(key_id)));
+    }
+    drop(store);
+    let mixed_size = log_size();
+
+    let store = KvStore::builder(temp_dir.path())
+        .compress_values(64)
+        .compaction_threshold(1)
+        .open()?;
+    store.set("small", "small")?;
+    wait_for_hints(temp_dir.path(), 1);
+    drop(store);
+    assert!(log_size() < compressed_size + (mixed_size - compressed_size) / 2);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..200 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(document(key_id)));
+    }
+    assert_eq!(store.get("small")?, Some("small".to_owned()));
+    Ok(())
+}
+
+// Encrypted logs don't contain the data in the clear, can't be opened
+// without the key, and compaction moves them over to a new key.
+#[test]
+fn encryption_at_rest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let old_key = EncryptionKey::generate();
+    let new_key = EncryptionKey::generate();
+    let is_wrong_key = |result: Result<KvStore>| {
+        matches!(
+            result.err().as_ref().and_then(|e| e.downcast_ref()),
+            Some(KvsError::WrongKey)
+        )
+    };
+
+    let store = KvStore::builder(temp_dir.path())
+        .encryption_key(old_key.clone())
+        .open()?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("secret{}", key_id))?;
+    }
+    drop(store);
+    let contents: Vec<u8> = list_files(temp_dir.path(), "log")
+        .iter()
+        .flat_map(|path| std::fs::read(path).unwrap())
+        .collect();
+    for needle in [&b"key42"[..], &b"secret42"[..]] {
+        assert!(!contents.windows(needle.len()).any(|w| w == needle));
+    }
+
+    assert!(is_wrong_key(KvStore::open(temp_dir.path())));
+    assert!(is_wrong_key(
+        KvStore::builder(temp_dir.path())
+            .encryption_key(new_key.clone())
+            .open()
+    ));
+
+    let store = KvStore::builder(temp_dir.path())
+        .encryption_key(new_key.clone())
+        .previous_key(old_key.clone())
+        .compaction_threshold(1)
+        .open()?;
+    assert_eq!(store.get("key7")?, Some("secret7".to_owned()));
+    store.set("key0", "rotated")?;
+    wait_for_hints(temp_dir.path(), 1);
+    drop(store);
+
+    let store = KvStore::builder(temp_dir.path())
+        .encryption_key(new_key)
+        .open()?;
+    assert_eq!(store.get("key0")?, Some("rotated".to_owned()));
+    for key_id in 1..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("secret{}", key_id))
+        );
+    }
+    drop(store);
+    assert!(is_wrong_key(
+        KvStore::builder(temp_dir.path())
+            .encryption_key(old_key)
+            .open()
+    ));
+    Ok(())
+}
+
+// The flags of an encrypted record are authenticated with it, so a record
+// whose flags were changed fails to read even with a valid checksum
+#[test]
+fn encrypted_record_flags_are_authenticated() -> Result<()> {
+    let key = EncryptionKey::generate();
+    for flag in [1u8, 2, 4] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::builder(temp_dir.path())
+            .encryption_key(key.clone())
+            .open()?;
+        store.set("key1", "value1")?;
+        let log = list_files(temp_dir.path(), "log").remove(0);
+        let first_len = std::fs::metadata(&log)?.len() - LOG_HEADER_LEN;
+        store.set("key2", "value2")?;
+        drop(store);
+
+        let mut contents = std::fs::read(&log)?;
+        let start = LOG_HEADER_LEN as usize;
+        contents[start + 13] ^= flag;
+        // a record with an expiry time is 8 bytes longer
+        let len = first_len as usize + if flag == 2 { 8 } else { 0 };
+        let crc = crc32fast::hash(&contents[start + 4..start + len]);
+        contents[start..start + 4].copy_from_slice(&crc.to_le_bytes());
+        std::fs::write(&log, contents)?;
+
+        let reopened = KvStore::builder(temp_dir.path())
+            .encryption_key(key.clone())
+            .open()
+            .and_then(|store| store.get("key1"));
+        assert!(reopened.is_err(), "flag {} was not authenticated", flag);
+    }
+    Ok(())
+}
+
+// A watcher sees every change to keys with its prefix, in order, and
+// nothing else
+#[test]
+fn watch_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let event = |key: &str, value: Option<&str>| Event {
+        key: key.into(),
+        op: if value.is_some() { Op::Set } else { Op::Remove },
+        value: value.map(Into::into),
+    };
+
+    let mut watcher = store.watch("user/")?;
+    store.set("user/1", "alice")?;
+    store.set("group/1", "admins")?;
+    store.append("user/1", "_smith")?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("user/2", "bob")
+        .remove("user/1")
+        .set("group/2", "guests");
+    store.write_batch(batch)?;
+    store.set_with_ttl("user/3", "carol", Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(10));
+    assert_eq!(store.get("user/3")?, None);
+
+    let events: Vec<Event> = watcher.by_ref().take(6).collect();
+    assert_eq!(
+        events,
+        [
+            event("user/1", Some("alice")),
+            event("user/1", Some("alice_smith")),
+            event("user/2", Some("bob")),
+            event("user/1", None),
+            event("user/3", Some("carol")),
+            event("user/3", None),
+        ]
+    );
+    assert!(watcher.next_timeout(Duration::from_millis(10)).is_err());
+
+    // a watcher that is not read ends once it falls too far behind, while
+    // the others go on
+    let lagging = store.watch("user/")?;
+    for iter in 0..10_000 {
+        store.set("user/1", format!("{}", iter))?;
+        assert_eq!(
+            watcher.next(),
+            Some(event("user/1", Some(&format!("{}", iter))))
+        );
+    }
+    let behind = lagging.count();
+    assert!(behind > 0 && behind < 10_000);
+
+    // the watcher ends with the store
+    let handle = thread::spawn(move || watcher.count());
+    drop(store);
+    assert_eq!(handle.join().unwrap(), 0);
+    Ok(())
+}
+
+// Trees are keyspaces of their own with their own logs, that persist and
+// are shared by every handle of the store
+#[test]
+fn trees() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path())
+        .compaction_threshold(1)
+        .open()?;
+    let users = store.open_tree("users")?;
+    let groups = users.open_tree("groups")?;
+    store.set("key1", "default")?;
+    users.set("key1", "alice")?;
+    groups.set("key1", "admins")?;
+    assert_eq!(store.get("key1")?, Some("default".to_owned()));
+    assert_eq!(users.get("key1")?, Some("alice".to_owned()));
+    assert_eq!(
+        store.open_tree("groups")?.get("key1")?,
+        Some("admins".to_owned())
+    );
+    assert!(temp_dir.path().join("trees/users").is_dir());
+    assert!(store.open_existing_tree("users")?.is_some());
+    assert!(store.open_existing_tree("missing")?.is_none());
+    assert!(!temp_dir.path().join("trees/missing").exists());
+    for name in ["", ".hidden", "a/b", "..", "x y"] {
+        assert!(store.open_tree(name).is_err());
+    }
+
+    // compacting a tree leaves the others alone
+    let default_logs = list_files(temp_dir.path(), "log").len();
+    for iter in 0..100 {
+        users.set("key2", format!("value{}", iter))?;
+    }
+    wait_for_hints(&temp_dir.path().join("trees/users"), 1);
+    assert_eq!(list_files(temp_dir.path(), "log").len(), default_logs);
+    drop(store);
+    drop(groups);
+    // the tree outlives its store, but can no longer reach the others
+    assert_eq!(users.get("key2")?, Some("value99".to_owned()));
+    assert!(users.open_tree("groups").is_err());
+    drop(users);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let users = store.open_tree("users")?;
+    assert_eq!(users.get("key1")?, Some("alice".to_owned()));
+    assert_eq!(users.get("key2")?, Some("value99".to_owned()));
+    users.remove("key1")?;
+    assert_eq!(store.get("key1")?, Some("default".to_owned()));
+    drop((store, users));
+
+    let store = KvStore::builder(temp_dir.path()).read_only(true).open()?;
+    assert_eq!(
+        store.open_tree("groups")?.get("key1")?,
+        Some("admins".to_owned())
+    );
+    assert!(store.open_tree("missing").is_err());
+    Ok(())
+}
+
+// Copies `files` into a new directory, each cut to the given length if
+// there is one, as a crash may have left them
+fn crash_state(files: &[(std::path::PathBuf, Option<u64>)]) -> TempDir {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    for (path, len) in files {
+        let mut contents = std::fs::read(path).unwrap();
+        if let Some(len) = len {
+            contents.truncate(*len as usize);
+        }
+        std::fs::write(dir.path().join(path.file_name().unwrap()), contents).unwrap();
+    }
+    dir
+}
+
+// A compaction only rewrites the files with dead bytes, and keeps the
+// tombstones that older files left alone still need. Removed keys stay
+// removed whichever step of the compaction a crash interrupts.
+#[test]
+fn compaction_crash_states() -> Result<()> {
+    let before = TempDir::new().expect("unable to create temporary working directory");
+    // Every open writes to a new file. The first only holds values, one of
+    // them merged onto later, so none of its bytes ever count as dead.
+    let store = KvStore::open(before.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value")?;
+    }
+    store.append("chain", "a")?;
+    drop(store);
+    let store = KvStore::open(before.path())?;
+    store.append("chain", "b")?;
+    store.set("gone", "1")?;
+    drop(store);
+    let store = KvStore::open(before.path())?;
+    store.remove("chain")?;
+    store.remove("gone")?;
+    // The removals hide values of older files and stay live, so the file
+    // needs more dead bytes than them to be compacted
+    store.set("moved", "1".repeat(100))?;
+    store.set("moved", "2")?;
+    drop(store);
+
+    let unseen = |path: &std::path::PathBuf| (path.clone(), None);
+    let mut files: Vec<_> = list_files(before.path(), "log")
+        .iter()
+        .map(unseen)
+        .collect();
+    files.push(unseen(&before.path().join("MANIFEST")));
+    let after = crash_state(&files);
+    let store = KvStore::builder(after.path())
+        .compaction_threshold(1)
+        .open()?;
+    // the manifest as it is until the compaction commits, which also lists
+    // the inputs but not the output
+    let stale = crash_state(&[unseen(&after.path().join("MANIFEST"))]);
+    store.set("trigger", "1")?;
+    wait_for_hints(after.path(), 1);
+    drop(store);
+    let stale_manifest = unseen(&stale.path().join("MANIFEST"));
+    let manifest = unseen(&after.path().join("MANIFEST"));
+
+    let output_hint = list_files(after.path(), "hint").remove(0);
+    let output = output_hint.with_extension("log");
+    let (kept, inputs): (Vec<_>, Vec<_>) = list_files(before.path(), "log")
+        .into_iter()
+        .partition(|path| after.path().join(path.file_name().unwrap()).exists());
+    assert_eq!(kept.len(), 1);
+    assert_eq!(inputs.len(), 2);
+    let others: Vec<_> = list_files(after.path(), "log")
+        .into_iter()
+        .filter(|path| *path != output)
+        .collect();
+
+    let check = |files: Vec<(std::path::PathBuf, Option<u64>)>| -> Result<TempDir> {
+        let dir = crash_state(&files);
+        let store = KvStore::open(dir.path())?;
+        assert_eq!(store.get("chain")?, None);
+        assert_eq!(store.get("gone")?, None);
+        assert_eq!(store.get("moved")?, Some("2".to_owned()));
+        assert_eq!(store.get("trigger")?, Some("1".to_owned()));
+        for key_id in 0..10 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some("value".to_owned())
+            );
+        }
+        Ok(dir)
+    };
+    // while copying: all the inputs, the output up to some record, and the
+    // manifest from before the commit
+    let len = std::fs::metadata(&output)?.len();
+    for cut in [8, len / 2, len - 1, len] {
+        let mut files: Vec<_> = others.iter().chain(&inputs).map(unseen).collect();
+        files.push((output.clone(), Some(cut)));
+        files.push(stale_manifest.clone());
+        check(files)?;
+    }
+    let mut files: Vec<_> = others.iter().chain(&inputs).map(unseen).collect();
+    files.extend([unseen(&output), unseen(&output_hint), stale_manifest]);
+    let dir = check(files)?;
+    assert!(list_files(dir.path(), "hint").is_empty());
+    // once committed, while the inputs are deleted oldest first, with the
+    // manifest or from before there was one
+    for deleted in 0..=inputs.len() {
+        let mut files: Vec<_> = others
+            .iter()
+            .chain(&inputs[deleted..])
+            .map(unseen)
+            .collect();
+        files.push(unseen(&output));
+        files.push(unseen(&output_hint));
+        check(files.clone())?;
+        files.push(manifest.clone());
+        let dir = check(files)?;
+        for input in &inputs {
+            assert!(!dir.path().join(input.file_name().unwrap()).exists());
+        }
+    }
+    // the same, with the hint lost
+    let mut files: Vec<_> = others.iter().chain([&output]).map(unseen).collect();
+    check(files.clone())?;
+    files.push(manifest);
+    check(files)?;
+    Ok(())
+}
+
+// A tombstone compaction carries over is live while an older file still
+// holds its key, and dead once that file is compacted away, whether the
+// store is opened from the hint or from the log. The segment holding it
+// is then compacted away in turn.
+#[test]
+fn tombstone_segment_is_removed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = |threshold| {
+        KvStore::builder(temp_dir.path())
+            .compaction_threshold(threshold)
+            .open()
+    };
+    // Every open writes to a new file. The first holds a large value that
+    // keeps it from being compacted while its small ones are removed by
+    // the second, whose other bytes are all dead once the third is written.
+    let store = open(u64::MAX)?;
+    store.set("anchor", "a".repeat(8192))?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), "value")?;
+    }
+    drop(store);
+    let store = open(u64::MAX)?;
+    for key_id in 0..50 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    store.set("junk", "j".repeat(8192))?;
+    drop(store);
+    let store = open(u64::MAX)?;
+    store.set("junk", "j")?;
+    drop(store);
+    let logs = list_files(temp_dir.path(), "log");
+    let (first, second) = (logs[0].clone(), logs[1].clone());
+
+    // The second file is compacted into one that only holds the tombstones
+    // of the first file's small values, which count as live
+    let store = open(1)?;
+    store.set("trigger", "1")?;
+    wait_for_hints(temp_dir.path(), 1);
+    drop(store);
+    assert!(!second.exists());
+    let segment = list_files(temp_dir.path(), "hint").remove(0);
+    let segment_id: u64 = segment
+        .file_stem()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    let store = open(u64::MAX)?;
+    let stats = store.file_stats();
+    let tombstones = stats.iter().find(|s| s.file_id == segment_id).unwrap();
+    assert!(tombstones.bytes > 0);
+    assert_eq!(tombstones.dead_bytes, 0);
+    drop(store);
+
+    // Overwriting the large value gets the first file compacted away
+    let store = open(1)?;
+    store.set("anchor", "a")?;
+    drop(store);
+    assert!(!first.exists());
+
+    // With nothing left for them to remove, the tombstones are dead on
+    // open, from the hint and from the log alike
+    let mut files: Vec<_> = list_files(temp_dir.path(), "log")
+        .iter()
+        .map(|path| (path.clone(), None))
+        .collect();
+    files.push((temp_dir.path().join("MANIFEST"), None));
+    let without_hint = crash_state(&files);
+    for dir in [temp_dir.path(), without_hint.path()] {
+        let store = KvStore::open(dir)?;
+        let stats = store.file_stats();
+        let tombstones = stats.iter().find(|s| s.file_id == segment_id).unwrap();
+        assert_eq!(tombstones.dead_bytes, tombstones.bytes);
+    }
+
+    let store = open(1)?;
+    store.set("trigger", "2")?;
+    drop(store);
+    assert!(!segment.exists());
+    assert!(!segment.with_extension("log").exists());
+    let store = open(u64::MAX)?;
+    for key_id in 0..50 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+    assert_eq!(store.get("anchor")?, Some("a".to_owned()));
+    assert_eq!(store.get("junk")?, Some("j".to_owned()));
+    Ok(())
+}
+
+// The manifest decides which files make up the store: those it does not
+// list are left over from a crash and deleted on open
+#[test]
+fn manifest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = |name: &str| temp_dir.path().join(name);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "old")?;
+    drop(store);
+    assert!(path("MANIFEST").exists());
+    let stale = std::fs::read(list_files(temp_dir.path(), "log").remove(0))?;
+
+    let store = KvStore::builder(temp_dir.path())
+        .compaction_threshold(1)
+        .open()?;
+    store.set("key1", "new")?;
+    wait_for_hints(temp_dir.path(), 1);
+    drop(store);
+
+    // what a compaction that never committed leaves behind
+    std::fs::write(path("1000.log"), &stale)?;
+    std::fs::write(path("1000.hint.tmp"), b"partial")?;
+    std::fs::write(path("MANIFEST.tmp"), b"partial")?;
+    let store = KvStore::builder(temp_dir.path()).read_only(true).open()?;
+    assert_eq!(store.get("key1")?, Some("new".to_owned()));
+    drop(store);
+    assert!(path("1000.log").exists());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("new".to_owned()));
+    drop(store);
+    for name in ["1000.log", "1000.hint.tmp", "MANIFEST.tmp"] {
+        assert!(!path(name).exists(), "{} was not removed", name);
+    }
+
+    // without a manifest, every log file is part of the store
+    std::fs::remove_file(path("MANIFEST"))?;
+    std::fs::write(path("1000.log"), &stale)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("old".to_owned()));
+    drop(store);
+    assert!(path("MANIFEST").exists());
+
+    // and every file it lists has to be there
+    std::fs::remove_file(path("1000.log"))?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    std::fs::write(path("MANIFEST"), b"garbage")?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    Ok(())
+}